@@ -0,0 +1,77 @@
+//! Measures throughput against a single hot route.
+//!
+//! Every client hits the same route, whose handler yields to the runtime for a few milliseconds,
+//! so any per-route locking shows up directly as lost throughput.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use zeke::{Handler, Response, Route, Router};
+
+const CLIENTS: usize = 50;
+const REQUESTS_PER_CLIENT: usize = 40;
+
+fn handle_hot() -> Handler {
+    return Handler::new(|request| {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let response = Response::new()
+                .status(200)
+                .body("hot");
+            return (request, response);
+        })
+    });
+}
+
+async fn send_request(addr: SocketAddr) -> bool {
+    let socket = TcpStream::connect(addr).await;
+    if socket.is_err() {
+        return false;
+    }
+    let mut socket = socket.unwrap();
+    if socket.write_all(b"GET /hot HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.is_err() {
+        return false;
+    }
+    let mut response = Vec::new();
+    if socket.read_to_end(&mut response).await.is_err() {
+        return false;
+    }
+    return response.starts_with(b"HTTP/1.1 200");
+}
+
+#[tokio::main]
+async fn main() {
+    let mut r = Router::new();
+    r.add(Route::new("GET /hot", handle_hot()));
+    // bound to a free port before `spawn` returns, so there is nothing to wait for
+    let server = r.spawn("127.0.0.1:0").await.expect("failed to start the server");
+    let addr = server.local_addr();
+
+    let start = Instant::now();
+    let mut clients = vec![];
+    for _ in 0..CLIENTS {
+        clients.push(tokio::spawn(async move {
+            let mut ok = 0;
+            for _ in 0..REQUESTS_PER_CLIENT {
+                if send_request(addr).await {
+                    ok += 1;
+                }
+            }
+            return ok;
+        }));
+    }
+    let mut ok = 0;
+    for client in clients {
+        ok += client.await.unwrap_or(0);
+    }
+    let elapsed = start.elapsed();
+    let total = CLIENTS * REQUESTS_PER_CLIENT;
+    println!("{}/{} requests succeeded in {:?}", ok, total, elapsed);
+    println!("{:.0} requests/sec", ok as f64 / elapsed.as_secs_f64());
+    let _ = server.shutdown().await;
+}
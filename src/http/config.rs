@@ -0,0 +1,271 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+use crate::http::framing::{MAX_BODY_SIZE, MAX_HEADER_SIZE};
+
+/// How long a fresh connection has to send its first request, and how long each read of a request may take.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a single write to the client may take.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a kept-alive connection may sit idle between requests before it is closed.
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many requests a single connection may make before it is closed.
+pub const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+/// How long in-flight requests get to finish once a shutdown starts, before their connections are aborted.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many connections may wait to be accepted before the OS starts refusing them.
+pub const BACKLOG: u32 = 1024;
+/// The default permissions of a Unix socket file: the owner and group can connect.
+pub const UNIX_SOCKET_MODE: u32 = 0o660;
+/// How many connections may be open at once by default.
+pub const MAX_CONNECTIONS: usize = 10_000;
+/// The most `ServerConfig.max_connections` can be set to, which is as many permits as a semaphore can hand out.
+pub const MAX_CONNECTIONS_LIMIT: usize = Semaphore::MAX_PERMITS;
+
+/// What happens to new connections while `ServerConfig.max_connections` are already open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadPolicy {
+    /// Stop accepting until a connection closes. New connections wait in the listen backlog.
+    Pause,
+    /// Keep accepting, but answer new connections with a 503 and close them straight away.
+    Shed,
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "pause" => Ok(OverloadPolicy::Pause),
+            "shed" => Ok(OverloadPolicy::Shed),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An environment variable that was set to something that couldn't be parsed.
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub key: String,
+    pub value: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid value for {}: `{}`", self.key, self.value)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Timeouts, limits and socket options for a server. `ServerConfig::new()` has the defaults,
+/// and each setter returns the config so they can be chained.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub keep_alive_timeout: Duration,
+    pub max_requests_per_connection: usize,
+    pub shutdown_timeout: Duration,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    /// Sets TCP_NODELAY on accepted connections, so small responses aren't held back by Nagle's algorithm.
+    pub tcp_nodelay: bool,
+    /// When set, enables SO_KEEPALIVE on accepted connections, probing after they have been idle this long.
+    pub tcp_keepalive: Option<Duration>,
+    pub backlog: u32,
+    /// The permissions given to the socket file by `Router::serve_unix`, or `None` to leave them to the umask.
+    pub unix_socket_mode: Option<u32>,
+    /// How many connections may be open at once, across the whole server.
+    pub max_connections: usize,
+    pub overload_policy: OverloadPolicy,
+    /// Serves cleartext HTTP/2 to clients that start with its preface or ask to upgrade with `Upgrade: h2c`. Off by default.
+    pub http2: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            read_timeout: READ_TIMEOUT,
+            write_timeout: WRITE_TIMEOUT,
+            keep_alive_timeout: KEEP_ALIVE_TIMEOUT,
+            max_requests_per_connection: MAX_REQUESTS_PER_CONNECTION,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            max_header_size: MAX_HEADER_SIZE,
+            max_body_size: MAX_BODY_SIZE,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            backlog: BACKLOG,
+            unix_socket_mode: Some(UNIX_SOCKET_MODE),
+            max_connections: MAX_CONNECTIONS,
+            overload_policy: OverloadPolicy::Pause,
+            http2: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        return ServerConfig::default();
+    }
+    /// Starts from the defaults and overrides anything set in the environment (including a `.env` file loaded with `dotenv`):
+    ///
+    /// - `ZEKE_READ_TIMEOUT_MS`, `ZEKE_WRITE_TIMEOUT_MS`, `ZEKE_KEEP_ALIVE_TIMEOUT_MS`, `ZEKE_SHUTDOWN_TIMEOUT_MS`
+    /// - `ZEKE_MAX_REQUESTS_PER_CONNECTION`, `ZEKE_MAX_HEADER_SIZE`, `ZEKE_MAX_BODY_SIZE`, `ZEKE_BACKLOG`
+    /// - `ZEKE_TCP_NODELAY` (`true` or `false`) and `ZEKE_TCP_KEEPALIVE_MS` (`0` turns it off)
+    /// - `ZEKE_UNIX_SOCKET_MODE`, in octal like `660`
+    /// - `ZEKE_MAX_CONNECTIONS` and `ZEKE_OVERLOAD_POLICY` (`pause` or `shed`)
+    /// - `ZEKE_HTTP2` (`true` or `false`)
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut config = ServerConfig::default();
+        if let Some(ms) = env_value::<u64>("ZEKE_READ_TIMEOUT_MS")? {
+            config.read_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_value::<u64>("ZEKE_WRITE_TIMEOUT_MS")? {
+            config.write_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_value::<u64>("ZEKE_KEEP_ALIVE_TIMEOUT_MS")? {
+            config.keep_alive_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_value::<u64>("ZEKE_SHUTDOWN_TIMEOUT_MS")? {
+            config.shutdown_timeout = Duration::from_millis(ms);
+        }
+        if let Some(count) = env_value::<usize>("ZEKE_MAX_REQUESTS_PER_CONNECTION")? {
+            config.max_requests_per_connection = count;
+        }
+        if let Some(bytes) = env_value::<usize>("ZEKE_MAX_HEADER_SIZE")? {
+            config.max_header_size = bytes;
+        }
+        if let Some(bytes) = env_value::<usize>("ZEKE_MAX_BODY_SIZE")? {
+            config.max_body_size = bytes;
+        }
+        if let Some(backlog) = env_value::<u32>("ZEKE_BACKLOG")? {
+            config.backlog = backlog;
+        }
+        if let Some(nodelay) = env_value::<bool>("ZEKE_TCP_NODELAY")? {
+            config.tcp_nodelay = nodelay;
+        }
+        if let Some(ms) = env_value::<u64>("ZEKE_TCP_KEEPALIVE_MS")? {
+            config.tcp_keepalive = if ms == 0 { None } else { Some(Duration::from_millis(ms)) };
+        }
+        if let Some(count) = env_value::<usize>("ZEKE_MAX_CONNECTIONS")? {
+            if count == 0 || count > MAX_CONNECTIONS_LIMIT {
+                // with none, no connection could ever be accepted
+                return Err(ConfigError {
+                    key: "ZEKE_MAX_CONNECTIONS".to_string(),
+                    value: count.to_string(),
+                });
+            }
+            config.max_connections = count;
+        }
+        if let Some(policy) = env_value::<OverloadPolicy>("ZEKE_OVERLOAD_POLICY")? {
+            config.overload_policy = policy;
+        }
+        if let Some(http2) = env_value::<bool>("ZEKE_HTTP2")? {
+            config.http2 = http2;
+        }
+        if let Some(mode) = env_value::<String>("ZEKE_UNIX_SOCKET_MODE")? {
+            match u32::from_str_radix(&mode, 8) {
+                Ok(mode) => {
+                    config.unix_socket_mode = Some(mode);
+                },
+                Err(_) => {
+                    return Err(ConfigError {
+                        key: "ZEKE_UNIX_SOCKET_MODE".to_string(),
+                        value: mode,
+                    });
+                },
+            }
+        }
+        return Ok(config);
+    }
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        return self;
+    }
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        return self;
+    }
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        return self;
+    }
+    pub fn max_requests_per_connection(mut self, count: usize) -> Self {
+        self.max_requests_per_connection = count;
+        return self;
+    }
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        return self;
+    }
+    /// Limits the request line plus headers to `bytes`. Larger requests are answered with 431.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.max_header_size = bytes;
+        return self;
+    }
+    /// Limits request bodies to `bytes`. Larger bodies are answered with 413 before they are read.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        return self;
+    }
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        return self;
+    }
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.tcp_keepalive = idle;
+        return self;
+    }
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        return self;
+    }
+    pub fn unix_socket_mode(mut self, mode: Option<u32>) -> Self {
+        self.unix_socket_mode = mode;
+        return self;
+    }
+    /// Limits how many connections may be open at once. Panics if `count` is zero, since no connection could be accepted,
+    /// or over `MAX_CONNECTIONS_LIMIT`.
+    pub fn max_connections(mut self, count: usize) -> Self {
+        assert!(count > 0 && count <= MAX_CONNECTIONS_LIMIT, "max_connections must be between 1 and {}", MAX_CONNECTIONS_LIMIT);
+        self.max_connections = count;
+        return self;
+    }
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload_policy = policy;
+        return self;
+    }
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.http2 = enabled;
+        return self;
+    }
+}
+
+/// Reads and parses `key`, treating an unset or empty variable as not configured.
+fn env_value<T: FromStr>(key: &str) -> Result<Option<T>, ConfigError> {
+    let value = match env::var(key) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok(None);
+        },
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<T>() {
+        Ok(parsed) => {
+            return Ok(Some(parsed));
+        },
+        Err(_) => {
+            return Err(ConfigError {
+                key: key.to_string(),
+                value: value.to_string(),
+            });
+        },
+    }
+}
@@ -0,0 +1,460 @@
+use std::fmt;
+
+use crate::http::response::Response;
+
+/// The default limit on the request line plus headers, in bytes.
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// The default limit on a request body, in bytes.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Why the framing of a request was rejected. The socket layer answers it with `response()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FramingError {
+    /// The request can't be parsed: 400.
+    Malformed(String),
+    /// The body is over the size limit: 413.
+    BodyTooLarge,
+    /// The trailers after a chunked body are over the size limit: 431.
+    TrailersTooLarge,
+    /// An `Expect` other than `100-continue`: 417.
+    ExpectationFailed,
+    /// A transfer coding other than chunked: 501.
+    UnsupportedEncoding,
+}
+
+impl FramingError {
+    pub fn status(&self) -> u16 {
+        match self {
+            FramingError::Malformed(_) => 400,
+            FramingError::BodyTooLarge => 413,
+            FramingError::TrailersTooLarge => 431,
+            FramingError::ExpectationFailed => 417,
+            FramingError::UnsupportedEncoding => 501,
+        }
+    }
+    pub fn response(&self) -> Response {
+        return Response::new()
+            .status(self.status())
+            .body(&self.to_string());
+    }
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FramingError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            FramingError::BodyTooLarge => write!(f, "request body too large"),
+            FramingError::TrailersTooLarge => write!(f, "request trailers too large"),
+            FramingError::ExpectationFailed => write!(f, "expectation failed"),
+            FramingError::UnsupportedEncoding => write!(f, "transfer encoding not supported"),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+/// Returns the index just past the blank line that ends the headers, if it has arrived yet.
+/// Lines may end in a bare LF as well as CRLF, as RFC 9112 allows, so `nc` and friends work too.
+pub fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    for (position, byte) in buffer.iter().enumerate() {
+        if *byte != b'\n' {
+            continue;
+        }
+        let rest = &buffer[position + 1..];
+        if rest.starts_with(b"\n") {
+            return Some(position + 2);
+        }
+        if rest.starts_with(b"\r\n") {
+            return Some(position + 3);
+        }
+    }
+    return None;
+}
+
+/// The lines of a request head, with their CRLF or bare LF endings removed.
+pub fn head_lines(head: &[u8]) -> Vec<String> {
+    let head = String::from_utf8_lossy(head);
+    return head.lines().map(|line| line.to_string()).collect();
+}
+
+/// Looks a header up in the raw head of a request, ignoring case and surrounding whitespace.
+/// Only the first is returned if the header was sent more than once.
+pub fn find_header(head: &[u8], key: &str) -> Option<String> {
+    return find_headers(head, key).into_iter().next();
+}
+
+/// Every value of a header in the raw head of a request, in the order they were sent.
+pub fn find_headers(head: &[u8], key: &str) -> Vec<String> {
+    let mut values: Vec<String> = vec![];
+    for line in head_lines(head).iter().skip(1) {
+        let parts = line.split_once(':');
+        if parts.is_none() {
+            continue;
+        }
+        let (name, value) = parts.unwrap();
+        if name.trim().eq_ignore_ascii_case(key) {
+            values.push(value.trim().to_string());
+        }
+    }
+    return values;
+}
+
+/// Reads the `Content-Length` of a request head, which is zero when the header is absent.
+/// Rejects a length that isn't all digits, or several lengths that disagree, with 400 (RFC 9112 §6.3),
+/// since a proxy in front could read the body differently. A length over `max_body_size` gets 413.
+pub fn content_length(head: &[u8], max_body_size: usize) -> Result<usize, FramingError> {
+    let mut length: Option<usize> = None;
+    for values in find_headers(head, "Content-Length") {
+        // repeats may also be sent as a list, like `Content-Length: 5, 5`
+        for value in values.split(',').map(|value| value.trim()) {
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(FramingError::Malformed("Content-Length is not a number".to_string()));
+            }
+            // all digits, so the only way to fail is by not fitting in a usize
+            let value = value.parse::<usize>().unwrap_or(usize::MAX);
+            if length.is_some_and(|length| length != value) {
+                return Err(FramingError::Malformed("Content-Length values disagree".to_string()));
+            }
+            length = Some(value);
+        }
+    }
+    let length = length.unwrap_or(0);
+    if length > max_body_size {
+        return Err(FramingError::BodyTooLarge);
+    }
+    return Ok(length);
+}
+
+/// Whether the head of a request declares a chunked body. Rejects a request that also sends
+/// `Content-Length` with 400, since the two disagree on where the body ends, and any transfer coding
+/// other than `chunked` alone with 501, including one applied before it like `gzip, chunked`.
+pub fn is_chunked(head: &[u8]) -> Result<bool, FramingError> {
+    let encodings = find_headers(head, "Transfer-Encoding");
+    if encodings.is_empty() {
+        return Ok(false);
+    }
+    if find_header(head, "Content-Length").is_some() {
+        return Err(FramingError::Malformed("both Transfer-Encoding and Content-Length were sent".to_string()));
+    }
+    // repeated headers are one list, so `gzip` and `chunked` sent separately are the same as `gzip, chunked`
+    let encodings = encodings.join(",");
+    let mut codings = encodings.split(',').map(|coding| coding.trim()).filter(|coding| !coding.is_empty());
+    if !codings.next().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) || codings.next().is_some() {
+        return Err(FramingError::UnsupportedEncoding);
+    }
+    return Ok(true);
+}
+
+/// Whether the client is waiting for `100 Continue` before it sends the body.
+/// Only HTTP/1.1 clients are honoured, and any expectation other than `100-continue` is refused with 417.
+pub fn expects_continue(head: &[u8]) -> Result<bool, FramingError> {
+    let expect = find_header(head, "Expect");
+    if expect.is_none() {
+        return Ok(false);
+    }
+    if !expect.unwrap().eq_ignore_ascii_case("100-continue") {
+        return Err(FramingError::ExpectationFailed);
+    }
+    let request_line = head_lines(head).into_iter().next().unwrap_or_default();
+    return Ok(request_line.ends_with("HTTP/1.1"));
+}
+
+/// How the body following a request head is delimited.
+pub enum BodyFraming {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+/// A request head that has been read in full, and how to read the body after it.
+pub struct RequestHead {
+    /// The index just past the blank line ending the head.
+    pub header_end: usize,
+    pub body: BodyFraming,
+}
+
+impl RequestHead {
+    /// Works out the framing of the body after `head`, which ends with its blank line.
+    pub fn new(head: &[u8], max_header_size: usize, max_body_size: usize) -> Result<RequestHead, FramingError> {
+        let body = if is_chunked(head)? {
+            BodyFraming::Chunked(ChunkedDecoder::new(max_body_size, max_header_size.saturating_sub(head.len())))
+        } else {
+            BodyFraming::Length(content_length(head, max_body_size)?)
+        };
+        return Ok(RequestHead {
+            header_end: head.len(),
+            body,
+        });
+    }
+    pub fn has_body(&self) -> bool {
+        match &self.body {
+            BodyFraming::Length(length) => *length > 0,
+            BodyFraming::Chunked(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkedState {
+    Size,
+    Data,
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Fields that can't be sent as trailers, because they frame, route, authenticate or control the request
+/// and so must be known before the body is read (RFC 9110 §6.5.1).
+const FORBIDDEN_TRAILERS: [&str; 18] = [
+    "authorization", "cache-control", "connection", "content-encoding", "content-length", "content-range",
+    "content-type", "cookie", "expect", "host", "keep-alive", "max-forwards", "pragma", "range", "te", "trailer",
+    "transfer-encoding", "upgrade",
+];
+
+fn is_forbidden_trailer(name: &str) -> bool {
+    let name = name.trim().to_ascii_lowercase();
+    return name.starts_with("proxy-") || FORBIDDEN_TRAILERS.contains(&name.as_str());
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body as its bytes arrive.
+/// Each call to `decode` picks up where the last one stopped, so nothing is parsed twice.
+pub struct ChunkedDecoder {
+    pub body: Vec<u8>,
+    /// Trailer fields sent after the last chunk, as raw `Key: Value` lines. Fields that aren't allowed in trailers are dropped.
+    pub trailers: Vec<String>,
+    /// How many of the chunked bytes have been consumed so far.
+    pub position: usize,
+    remaining: usize,
+    trailers_size: usize,
+    state: ChunkedState,
+    max_body_size: usize,
+    max_trailers_size: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new(max_body_size: usize, max_trailers_size: usize) -> ChunkedDecoder {
+        ChunkedDecoder {
+            body: vec![],
+            trailers: vec![],
+            position: 0,
+            remaining: 0,
+            trailers_size: 0,
+            state: ChunkedState::Size,
+            max_body_size,
+            max_trailers_size,
+        }
+    }
+
+    /// Decodes as much of `bytes` (everything received after the head) as possible.
+    /// Returns `Ok(true)` once the last chunk and the trailers have been read, `Ok(false)` when more bytes are needed,
+    /// and an error when the framing is broken or the body or trailers are too large.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<bool, FramingError> {
+        loop {
+            let available = &bytes[self.position..];
+            match self.state {
+                ChunkedState::Size => {
+                    let line_end = available.windows(2).position(|window| window == b"\r\n");
+                    if line_end.is_none() {
+                        if available.len() > 1024 {
+                            return Err(ChunkedDecoder::malformed("chunk size line is too long"));
+                        }
+                        return Ok(false);
+                    }
+                    let line_end = line_end.unwrap();
+                    let line = String::from_utf8_lossy(&available[..line_end]).to_string();
+                    // chunk extensions after `;` carry nothing we use
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    // `from_str_radix` would also take a leading `+`
+                    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                        return Err(ChunkedDecoder::malformed("chunk size is not a hex number"));
+                    }
+                    // all hex digits, so the only way to fail is by not fitting in a usize
+                    let size = usize::from_str_radix(size, 16).unwrap_or(usize::MAX);
+                    self.position += line_end + 2;
+                    if size == 0 {
+                        self.state = ChunkedState::Trailers;
+                        continue;
+                    }
+                    if self.body.len().saturating_add(size) > self.max_body_size {
+                        return Err(FramingError::BodyTooLarge);
+                    }
+                    self.remaining = size;
+                    self.state = ChunkedState::Data;
+                },
+                ChunkedState::Data => {
+                    let take = self.remaining.min(available.len());
+                    self.body.extend_from_slice(&available[..take]);
+                    self.position += take;
+                    self.remaining -= take;
+                    if self.remaining > 0 {
+                        return Ok(false);
+                    }
+                    self.state = ChunkedState::DataEnd;
+                },
+                ChunkedState::DataEnd => {
+                    if available.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &available[..2] != b"\r\n" {
+                        return Err(ChunkedDecoder::malformed("chunk data was not followed by CRLF"));
+                    }
+                    self.position += 2;
+                    self.state = ChunkedState::Size;
+                },
+                ChunkedState::Trailers => {
+                    let line_end = available.windows(2).position(|window| window == b"\r\n");
+                    if line_end.is_none() {
+                        if self.trailers_size + available.len() > self.max_trailers_size {
+                            return Err(FramingError::TrailersTooLarge);
+                        }
+                        return Ok(false);
+                    }
+                    let line_end = line_end.unwrap();
+                    self.position += line_end + 2;
+                    if line_end == 0 {
+                        self.state = ChunkedState::Done;
+                        continue;
+                    }
+                    self.trailers_size += line_end + 2;
+                    if self.trailers_size > self.max_trailers_size {
+                        return Err(FramingError::TrailersTooLarge);
+                    }
+                    let line = String::from_utf8_lossy(&available[..line_end]).to_string();
+                    let name = line.split_once(':').map(|(name, _)| name);
+                    if name.is_none() {
+                        return Err(ChunkedDecoder::malformed("trailer line did not contain a colon"));
+                    }
+                    if !is_forbidden_trailer(name.unwrap()) {
+                        self.trailers.push(line);
+                    }
+                },
+                ChunkedState::Done => {
+                    return Ok(true);
+                },
+            }
+        }
+    }
+
+    /// Rebuilds the request as if the decoded body had been sent whole, with the trailers appended to `head`'s headers.
+    /// A trailer never replaces a header: one whose name is already in `head` is dropped.
+    pub fn request_bytes(&self, head: &[u8]) -> Vec<u8> {
+        // drop the blank line ending the head so the trailers land among the headers
+        let blank_line = if head.ends_with(b"\n\r\n") { 2 } else { 1 };
+        let mut request_bytes = head[..head.len() - blank_line].to_vec();
+        for trailer in &self.trailers {
+            let name = trailer.split_once(':').map(|(name, _)| name.trim()).unwrap_or("");
+            if find_header(head, name).is_some() {
+                continue;
+            }
+            request_bytes.extend_from_slice(trailer.as_bytes());
+            request_bytes.extend_from_slice(b"\r\n");
+        }
+        request_bytes.extend_from_slice(b"\r\n");
+        request_bytes.extend_from_slice(&self.body);
+        return request_bytes;
+    }
+
+    fn malformed(reason: &str) -> FramingError {
+        return FramingError::Malformed(reason.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_end_with_crlf_or_lf() {
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody"), Some(27));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\nHost: a\n\nbody"), Some(24));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\nHost: a\n\r\nbody"), Some(25));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+    }
+
+    #[test]
+    fn headers_in_lf_only_heads() {
+        let head = b"POST / HTTP/1.1\nContent-Length: 5\nExpect: 100-continue\n\n";
+        assert_eq!(content_length(head, 100), Ok(5));
+        assert_eq!(expects_continue(head), Ok(true));
+    }
+
+    #[test]
+    fn repeated_content_length() {
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n", 100), Ok(5));
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n", 100), Ok(5));
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n", 100).unwrap_err().status(), 400);
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n", 100).unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn framing_errors() {
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n", 100).unwrap_err().status(), 400);
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 101\r\n\r\n", 100), Err(FramingError::BodyTooLarge));
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n", 100).unwrap_err().status(), 400);
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: \r\n\r\n", 100).unwrap_err().status(), 400);
+        assert_eq!(content_length(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n", 100), Err(FramingError::BodyTooLarge));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), Err(FramingError::UnsupportedEncoding));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"), Err(FramingError::UnsupportedEncoding));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n"), Err(FramingError::UnsupportedEncoding));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n"), Err(FramingError::UnsupportedEncoding));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n"), Ok(true));
+        assert_eq!(is_chunked(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n").unwrap_err().status(), 400);
+        assert_eq!(expects_continue(b"POST / HTTP/1.1\r\nExpect: magic\r\n\r\n"), Err(FramingError::ExpectationFailed));
+        assert_eq!(expects_continue(b"POST / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n"), Ok(false));
+    }
+
+    fn decode_all(bytes: &[u8]) -> ChunkedDecoder {
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(bytes), Ok(true));
+        return decoder;
+    }
+
+    #[test]
+    fn chunked_split_reads() {
+        let bytes = b"3\r\nabc\r\na\r\n0123456789\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        for end in 0..bytes.len() {
+            assert_eq!(decoder.decode(&bytes[..end]), Ok(false));
+        }
+        assert_eq!(decoder.decode(bytes), Ok(true));
+        assert_eq!(decoder.body, b"abc0123456789");
+        assert_eq!(decoder.position, bytes.len());
+    }
+
+    #[test]
+    fn chunked_extensions_are_ignored() {
+        let decoder = decode_all(b"3;name=value\r\nabc\r\n0;last\r\n\r\n");
+        assert_eq!(decoder.body, b"abc");
+    }
+
+    #[test]
+    fn chunked_malformed() {
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(b"3\r\nabcX\r\n").unwrap_err().status(), 400);
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(b"zz\r\n").unwrap_err().status(), 400);
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(b"+3\r\nabc\r\n0\r\n\r\n").unwrap_err().status(), 400);
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(b";ext\r\n").unwrap_err().status(), 400);
+        let mut decoder = ChunkedDecoder::new(100, 100);
+        assert_eq!(decoder.decode(b"0\r\nno colon\r\n\r\n").unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn chunked_limits() {
+        let mut decoder = ChunkedDecoder::new(5, 100);
+        assert_eq!(decoder.decode(b"3\r\nabc\r\n3\r\n"), Err(FramingError::BodyTooLarge));
+        let mut decoder = ChunkedDecoder::new(100, 10);
+        assert_eq!(decoder.decode(b"0\r\nX-Long: 0123456789\r\n\r\n"), Err(FramingError::TrailersTooLarge));
+        let mut decoder = ChunkedDecoder::new(100, 10);
+        assert_eq!(decoder.decode(b"0\r\nX-Long: 0123456789"), Err(FramingError::TrailersTooLarge));
+    }
+
+    #[test]
+    fn chunked_trailers() {
+        let decoder = decode_all(b"3\r\nabc\r\n0\r\nX-Checksum: 1\r\nContent-Length: 99\r\nhost: evil\r\nX-Id: 2\r\n\r\n");
+        assert_eq!(decoder.trailers, vec!["X-Checksum: 1", "X-Id: 2"]);
+        let request = decoder.request_bytes(b"POST / HTTP/1.1\r\nHost: a\r\nx-id: 1\r\n\r\n");
+        assert_eq!(request, b"POST / HTTP/1.1\r\nHost: a\r\nx-id: 1\r\nX-Checksum: 1\r\n\r\nabc");
+    }
+}
@@ -1,13 +1,16 @@
-
-use tokio::sync::RwLock;
 use std::sync::Arc;
 use futures::future::BoxFuture;
 
 use crate::http::request::Request;
 use crate::http::response::Response;
+use crate::http::websocket::{websocket_response, WebSocket, WebSocketFunc};
+
+pub type HandlerFunc = dyn Fn(Request) -> BoxFuture<'static, (Request, Response)> + Send + Sync + 'static;
 
+/// Handlers are immutable once built, so concurrent requests call the same function without locking.
+#[derive(Clone)]
 pub struct Handler {
-    pub func: Arc<RwLock<dyn Fn(Request) -> BoxFuture<'static, (Request, Response)> + Send + Sync + 'static>>,
+    pub func: Arc<HandlerFunc>,
 }
 
 impl Handler {
@@ -16,7 +19,24 @@ impl Handler {
         F: Fn(Request) -> BoxFuture<'static, (Request, Response)> + Send + Sync + 'static,
     {
         Handler {
-            func: Arc::new(RwLock::new(f)),
+            func: Arc::new(f),
         }
     }
-}
\ No newline at end of file
+    /// A handler that accepts WebSocket connections. Middleware runs as it would for any other route, so the
+    /// handshake can be turned away before the upgrade, say for a missing session cookie. Once the client is
+    /// connected, `f` is given the request and the socket, and the connection stays open until `f` returns.
+    /// `f` isn't told when the server shuts down, so a graceful shutdown waits for it to return, for up to
+    /// `ServerConfig.shutdown_timeout`, before aborting it.
+    pub fn websocket<F>(f: F) -> Handler
+    where
+        F: Fn(Request, WebSocket) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        let func: Arc<WebSocketFunc> = Arc::new(f);
+        return Handler::new(move |request| {
+            let response = websocket_response(&request, Arc::clone(&func));
+            return Box::pin(async move {
+                return (request, response);
+            });
+        });
+    }
+}
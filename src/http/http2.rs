@@ -0,0 +1,454 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use h2::server::{self, SendResponse};
+use h2::{Reason, RecvStream, SendStream};
+use http::request::Parts;
+
+use tokio::io::AsyncRead;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+use crate::http::router::Router;
+use crate::http::response::{Response, Upgraded};
+use crate::http::request::{Request, RequestBuffer};
+use crate::http::framing::{find_header, head_lines};
+use crate::http::socket::{handle_request, handle_request_bytes, read_more, Connection, Rewind};
+use crate::http::shutdown::wait_for_shutdown;
+use crate::http::config::ServerConfig;
+
+/// The bytes every HTTP/2 connection starts with.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// How many requests a client may have in flight at once on one HTTP/2 connection.
+pub const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// The largest frame a peer has to accept before it says otherwise.
+const MAX_FRAME_SIZE: usize = 16_384;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+/// Headers that only mean something to a single HTTP/1.1 connection, which HTTP/2 forbids.
+const CONNECTION_HEADERS: [&str; 6] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "te"];
+
+/// Reads until `buffer` either holds the whole HTTP/2 preface or can't be the start of one, returning whether it does.
+pub async fn read_preface<S: AsyncRead + Unpin>(socket: &mut S, buffer: &mut RequestBuffer, config: &ServerConfig) -> bool {
+	loop {
+		let length = buffer.len().min(PREFACE.len());
+		if buffer[..length] != PREFACE[..length] {
+			return false;
+		}
+		if length == PREFACE.len() {
+			return true;
+		}
+		if read_more(socket, buffer, config).await.is_err() {
+			return false;
+		}
+	}
+}
+
+/// Whether the head of an HTTP/1.1 request asks to switch the connection to cleartext HTTP/2.
+pub fn is_h2c_upgrade(head: &[u8]) -> bool {
+	let upgrade = find_header(head, "Upgrade").unwrap_or_default();
+	let wants_h2c = upgrade.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"));
+	let request_line = head_lines(head).into_iter().next().unwrap_or_default();
+	return wants_h2c && request_line.ends_with(" HTTP/1.1") && upgrade_settings(head).is_some();
+}
+
+/// Decodes the `HTTP2-Settings` header of an upgrade request: the payload of a SETTINGS frame, base64url encoded.
+/// Returns `None` if it is missing or isn't a valid payload, in which case the request isn't upgraded.
+fn upgrade_settings(head: &[u8]) -> Option<Vec<u8>> {
+	let payload = decode_base64url(&find_header(head, "HTTP2-Settings")?)?;
+	// each setting is a 2 byte identifier and a 4 byte value
+	if payload.len() % 6 != 0 || payload.len() > MAX_FRAME_SIZE {
+		return None;
+	}
+	return Some(payload);
+}
+
+/// Decodes base64url, with or without padding.
+fn decode_base64url(value: &str) -> Option<Vec<u8>> {
+	let mut bytes: Vec<u8> = vec![];
+	let mut bits: u32 = 0;
+	let mut bit_count = 0;
+	for c in value.trim_end_matches('=').bytes() {
+		let digit = match c {
+			b'A'..=b'Z' => c - b'A',
+			b'a'..=b'z' => c - b'a' + 26,
+			b'0'..=b'9' => c - b'0' + 52,
+			b'-' => 62,
+			b'_' => 63,
+			_ => {
+				return None;
+			},
+		};
+		bits = (bits << 6) | digit as u32;
+		bit_count += 6;
+		if bit_count >= 8 {
+			bit_count -= 8;
+			bytes.push((bits >> bit_count) as u8);
+		}
+	}
+	return Some(bytes);
+}
+
+/// Answers a request that asked for `Upgrade: h2c` with `101 Switching Protocols`. HTTP/2 then takes over the connection,
+/// and the request that asked for it is answered on stream 1.
+pub fn h2c_upgrade(head: RequestBuffer, router: Arc<Router>, shutdown: watch::Receiver<bool>) -> Response {
+	return Response::new()
+		.set_header("Upgrade", "h2c")
+		.upgrade(move |upgraded| serve_upgraded(upgraded, head, router, shutdown));
+}
+
+/// Serves HTTP/2 on a connection that was upgraded from HTTP/1.1 by the request in `head`.
+/// h2 expects that request as a stream the client opened, so once the client's preface and first SETTINGS frame
+/// have arrived, it is replayed as a HEADERS frame for stream 1 in front of whatever the client sends next.
+/// The settings from the request's `HTTP2-Settings` header are the client's initial ones, so they are put in front of
+/// those in its SETTINGS frame, where the client's own can still override them and a single acknowledgement covers both.
+pub async fn serve_upgraded(mut upgraded: Upgraded, head: RequestBuffer, router: Arc<Router>, shutdown: watch::Receiver<bool>) {
+	let config = &router.config;
+	let mut buffer: RequestBuffer = vec![];
+	let settings_end = loop {
+		if buffer.len() >= PREFACE.len() + 9 {
+			let frame = &buffer[PREFACE.len()..];
+			if !buffer.starts_with(PREFACE) || frame[3] != FRAME_SETTINGS {
+				return;
+			}
+			let length = u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize;
+			if length > MAX_FRAME_SIZE {
+				return;
+			}
+			if frame.len() >= 9 + length {
+				break PREFACE.len() + 9 + length;
+			}
+		}
+		if read_more(&mut upgraded, &mut buffer, config).await.is_err() {
+			return;
+		}
+	};
+	let mut settings = upgrade_settings(&head).unwrap_or_default();
+	settings.extend_from_slice(&buffer[PREFACE.len() + 9..settings_end]);
+	if settings.len() > MAX_FRAME_SIZE {
+		return;
+	}
+	let mut replay = PREFACE.to_vec();
+	replay.extend_from_slice(&(settings.len() as u32).to_be_bytes()[1..]);
+	// the frame type, flags and stream id stay as the client sent them
+	replay.extend_from_slice(&buffer[PREFACE.len() + 3..PREFACE.len() + 9]);
+	replay.extend(settings);
+	replay.extend(upgrade_frames(&head));
+	replay.extend_from_slice(&buffer[settings_end..]);
+	serve_http2(Rewind::new(replay, upgraded), router, shutdown).await;
+}
+
+/// Encodes the head of an upgraded HTTP/1.1 request as the HEADERS (and if need be CONTINUATION) frames of stream 1.
+/// Every field is sent as an HPACK literal without indexing, so the client's header table is left alone.
+fn upgrade_frames(head: &[u8]) -> Vec<u8> {
+	let lines = head_lines(head);
+	let mut lines = lines.iter();
+	let request_line = lines.next().cloned().unwrap_or_default();
+	let mut request_line = request_line.split(' ');
+	let method = request_line.next().unwrap_or("GET").to_string();
+	let path = request_line.next().unwrap_or("/").to_string();
+	// pseudo-headers have to come before everything else
+	let mut fields: Vec<(String, String)> = vec![
+		(":method".to_string(), method),
+		(":scheme".to_string(), "http".to_string()),
+		(":path".to_string(), path),
+	];
+	let mut headers: Vec<(String, String)> = vec![];
+	for line in lines {
+		let (name, value) = match line.split_once(':') {
+			Some(field) => field,
+			None => {
+				continue;
+			},
+		};
+		let name = name.trim().to_ascii_lowercase();
+		let value = value.trim().to_string();
+		if name == "host" {
+			fields.push((":authority".to_string(), value));
+		} else if name != "http2-settings" && !CONNECTION_HEADERS.contains(&name.as_str()) {
+			headers.push((name, value));
+		}
+	}
+	fields.extend(headers);
+	let mut block: Vec<u8> = vec![];
+	for (name, value) in &fields {
+		block.push(0);
+		encode_string(&mut block, name);
+		encode_string(&mut block, value);
+	}
+	let mut frames: Vec<u8> = vec![];
+	let chunks: Vec<&[u8]> = block.chunks(MAX_FRAME_SIZE).collect();
+	for (index, chunk) in chunks.iter().enumerate() {
+		let (kind, mut flags) = if index == 0 { (FRAME_HEADERS, FLAG_END_STREAM) } else { (FRAME_CONTINUATION, 0) };
+		if index == chunks.len() - 1 {
+			flags |= FLAG_END_HEADERS;
+		}
+		frames.extend_from_slice(&(chunk.len() as u32).to_be_bytes()[1..]);
+		frames.extend_from_slice(&[kind, flags, 0, 0, 0, 1]);
+		frames.extend_from_slice(chunk);
+	}
+	return frames;
+}
+
+/// Appends an HPACK string literal, without Huffman coding.
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+	let mut length = value.len();
+	if length < 127 {
+		block.push(length as u8);
+	} else {
+		block.push(127);
+		length -= 127;
+		while length >= 128 {
+			block.push((length % 128) as u8 | 0x80);
+			length /= 128;
+		}
+		block.push(length as u8);
+	}
+	block.extend_from_slice(value.as_bytes());
+}
+
+/// Serves HTTP/2 on a connection until the client goes away, it sits without streams for `keep_alive_timeout`,
+/// or the server shuts down. Each stream is answered in its own task by the same chain of middleware and handlers
+/// as an HTTP/1.1 request. When closing, streams already in flight are finished before the connection is.
+pub async fn serve_http2<S: Connection>(io: S, router: Arc<Router>, mut shutdown: watch::Receiver<bool>) {
+	let config = &router.config;
+	let mut builder = server::Builder::new();
+	builder
+		.max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+		.max_header_list_size(config.max_header_size as u32);
+	let mut connection = match timeout(config.read_timeout, builder.handshake::<_, Bytes>(io)).await {
+		Ok(Ok(connection)) => connection,
+		_ => {
+			// TODO: log
+			return;
+		},
+	};
+	let mut streams: JoinSet<()> = JoinSet::new();
+	let mut closing = false;
+	loop {
+		// accepting also drives the connection, so it keeps being polled while the streams are answered
+		tokio::select! {
+			accepted = connection.accept() => {
+				match accepted {
+					Some(Ok((request, respond))) => {
+						streams.spawn(serve_stream(Arc::clone(&router), request, respond));
+					},
+					_ => {
+						// the connection closed, or failed
+						break;
+					},
+				}
+			},
+			Some(_) = streams.join_next(), if !streams.is_empty() => {},
+			_ = sleep(config.keep_alive_timeout), if streams.is_empty() && !closing => {
+				closing = true;
+				connection.graceful_shutdown();
+			},
+			_ = wait_for_shutdown(&mut shutdown), if !closing => {
+				closing = true;
+				connection.graceful_shutdown();
+			},
+		}
+	}
+}
+
+/// Answers one HTTP/2 stream. The request is read in full and turned into the same `Request` an HTTP/1.1 client
+/// would have produced, with `protocol` set to `HTTP/2.0`, so handlers never need to know the difference.
+pub async fn serve_stream(router: Arc<Router>, request: http::Request<RecvStream>, respond: SendResponse<Bytes>) {
+	let config = &router.config;
+	let (mut parts, mut body) = request.into_parts();
+	let declared_length = parts.headers.get(http::header::CONTENT_LENGTH)
+		.and_then(|length| length.to_str().ok())
+		.and_then(|length| length.parse::<usize>().ok())
+		.unwrap_or(0);
+	let too_large = Response::new()
+		.status(413)
+		.body("request body too large");
+	if declared_length > config.max_body_size {
+		send_response(respond, too_large, config.write_timeout).await;
+		return;
+	}
+	let mut body_bytes: Vec<u8> = vec![];
+	loop {
+		let chunk = match timeout(config.read_timeout, body.data()).await {
+			Ok(Some(Ok(chunk))) => chunk,
+			Ok(Some(Err(_))) => {
+				// the client reset the stream
+				return;
+			},
+			Ok(None) => {
+				break;
+			},
+			Err(_) => {
+				send_response(respond, Response::new().status(408).body("read timeout"), config.write_timeout).await;
+				return;
+			},
+		};
+		let _ = body.flow_control().release_capacity(chunk.len());
+		if body_bytes.len() + chunk.len() > config.max_body_size {
+			send_response(respond, too_large, config.write_timeout).await;
+			return;
+		}
+		body_bytes.extend_from_slice(&chunk);
+	}
+	// trailers join the headers, as they do for a chunked HTTP/1.1 body
+	if let Ok(Ok(Some(trailers))) = timeout(config.read_timeout, body.trailers()).await {
+		parts.headers.extend(trailers);
+	}
+	let request_bytes = http1_request_bytes(&parts, &body_bytes);
+	let (mut request, potential_response) = Request::new_from_bytes(request_bytes.clone());
+	let response = match potential_response {
+		Some(_) => handle_request_bytes(&router, request_bytes).await.0,
+		None => {
+			request.protocol = "HTTP/2.0".to_string();
+			handle_request(Arc::clone(&router), request).await
+		},
+	};
+	send_response(respond, response, config.write_timeout).await;
+}
+
+/// Writes an HTTP/2 request out as the HTTP/1.1 request it stands for, which is what `Request` parses.
+/// `:authority` becomes `Host`, cookies sent as separate fields are joined back together,
+/// and the body, already read in full, is framed by its length.
+fn http1_request_bytes(parts: &Parts, body: &[u8]) -> RequestBuffer {
+	let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+	let mut head = format!("{} {} HTTP/1.1\r\n", parts.method, path);
+	if let Some(authority) = parts.uri.authority() {
+		head.push_str(&format!("Host: {}\r\n", authority));
+	}
+	let mut cookies: Vec<String> = vec![];
+	for (name, value) in parts.headers.iter() {
+		let value = String::from_utf8_lossy(value.as_bytes());
+		match name.as_str() {
+			"cookie" => {
+				cookies.push(value.to_string());
+			},
+			"host" if parts.uri.authority().is_some() => {},
+			"content-length" | "transfer-encoding" => {},
+			name => {
+				head.push_str(&format!("{}: {}\r\n", title_case(name), value));
+			},
+		}
+	}
+	if !cookies.is_empty() {
+		head.push_str(&format!("Cookie: {}\r\n", cookies.join("; ")));
+	}
+	if !body.is_empty() {
+		head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+	}
+	head.push_str("\r\n");
+	let mut request_bytes = head.into_bytes();
+	request_bytes.extend_from_slice(body);
+	return request_bytes;
+}
+
+/// HTTP/2 sends header names in lower case, but handlers look them up the way HTTP/1.1 clients write them, like `Content-Type`.
+fn title_case(name: &str) -> String {
+	return name.split('-').map(|word| {
+		let mut chars = word.chars();
+		match chars.next() {
+			Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+			None => String::new(),
+		}
+	}).collect::<Vec<String>>().join("-");
+}
+
+/// Builds the HTTP/2 head of a response, leaving out the headers that only apply to an HTTP/1.1 connection.
+fn response_head(response: &Response) -> http::Response<()> {
+	// there is no switching protocols from a stream
+	if response.status < 200 {
+		return http::Response::builder().status(500).body(()).unwrap();
+	}
+	let mut builder = http::Response::builder().status(response.status);
+	for header in response.headers.iter() {
+		if CONNECTION_HEADERS.iter().any(|name| header.key().eq_ignore_ascii_case(name)) {
+			continue;
+		}
+		builder = builder.header(header.key().as_str(), header.value().trim());
+	}
+	for cookie in &response.cookies.cookies {
+		builder = builder.header("set-cookie", cookie.to_string());
+	}
+	let has_body = response.status != 204 && response.status != 304;
+	if !response.has_length() && response.stream.is_none() && has_body {
+		builder = builder.header("content-length", response.body.len());
+	}
+	match builder.body(()) {
+		Ok(head) => {
+			return head;
+		},
+		Err(_) => {
+			// TODO: log the header that couldn't be sent
+			return http::Response::builder().status(500).body(()).unwrap();
+		},
+	}
+}
+
+/// Sends a response on its stream, streaming the body when it has one. As over HTTP/1.1, each chunk is sent
+/// before the next one is pulled from the stream, and each send may take up to `write_timeout`.
+pub async fn send_response(mut respond: SendResponse<Bytes>, response: Response, write_timeout: Duration) {
+	let head = response_head(&response);
+	let stream = response.stream.as_ref().and_then(|stream| stream.take());
+	let body = Bytes::from(response.body.into_bytes());
+	let end_of_stream = stream.is_none() && body.is_empty();
+	let mut send = match respond.send_response(head, end_of_stream) {
+		Ok(send) => send,
+		Err(_) => {
+			return;
+		},
+	};
+	if end_of_stream {
+		return;
+	}
+	let mut stream = match stream {
+		Some(stream) => stream,
+		None => {
+			if !matches!(timeout(write_timeout, send_data(&mut send, body, true)).await, Ok(Ok(_))) {
+				send.send_reset(Reason::CANCEL);
+			}
+			return;
+		},
+	};
+	while let Some(chunk) = stream.next().await {
+		let chunk = match chunk {
+			Ok(chunk) => chunk,
+			Err(_) => {
+				// the head is already out, so all we can do is cut the body short
+				send.send_reset(Reason::INTERNAL_ERROR);
+				return;
+			},
+		};
+		if !matches!(timeout(write_timeout, send_data(&mut send, Bytes::from(chunk), false)).await, Ok(Ok(_))) {
+			send.send_reset(Reason::CANCEL);
+			return;
+		}
+	}
+	let _ = send.send_data(Bytes::new(), true);
+}
+
+/// Sends `data` as fast as the client's flow control window allows.
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes, end_of_stream: bool) -> Result<(), h2::Error> {
+	if data.is_empty() {
+		return send.send_data(data, end_of_stream);
+	}
+	while !data.is_empty() {
+		send.reserve_capacity(data.len());
+		let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+			Some(capacity) => capacity?,
+			None => {
+				return Err(h2::Error::from(Reason::CANCEL));
+			},
+		};
+		let chunk = data.split_to(capacity.min(data.len()));
+		send.send_data(chunk, end_of_stream && data.is_empty())?;
+	}
+	return Ok(());
+}
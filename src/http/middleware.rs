@@ -1,5 +1,4 @@
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use futures::future::{BoxFuture, FutureExt}; // Import FutureExt for .boxed()
 
 use crate::http::request::Request;
@@ -7,8 +6,9 @@ use crate::http::response::Response;
 
 pub type MiddlewareFunc = dyn Fn(&mut Request) -> BoxFuture<'static, Option<Response>> + Send + Sync + 'static;
 
+#[derive(Clone)]
 pub struct Middleware {
-    pub func: Arc<RwLock<Box<MiddlewareFunc>>>,
+    pub func: Arc<MiddlewareFunc>,
 }
 
 impl Middleware {
@@ -22,13 +22,14 @@ impl Middleware {
         };
 
         Middleware {
-            func: Arc::new(RwLock::new(Box::new(wrapped_func))),
+            func: Arc::new(wrapped_func),
         }
     }
 }
 
 pub type Middlewares = Vec<Middleware>;
 
+#[derive(Clone)]
 pub struct MiddlewareGroup {
     pub middlewares: Middlewares,
     pub outerwares: Middlewares,
@@ -1,10 +1,20 @@
 pub mod response;
 pub mod socket;
 pub mod router;
+pub mod tree;
 pub mod request;
 pub mod middleware;
 pub mod handler;
 pub mod logger;
 pub mod timer;
 pub mod fuzzer;
-pub mod cookie;
\ No newline at end of file
+pub mod cookie;
+pub mod openapi;
+pub mod framing;
+pub mod shutdown;
+pub mod config;
+pub mod tls;
+pub mod server;
+pub mod http2;
+pub mod websocket;
+pub mod sse;
@@ -0,0 +1,88 @@
+use serde_json::{json, Map, Value};
+
+use crate::http::router::Router;
+use crate::http::tree::path_segments;
+
+/// Converts a route path like `/users/:id/*rest` into OpenAPI form, `/users/{id}/{rest}`,
+/// returning the names of the path parameters alongside it.
+pub fn openapi_path(path: &str) -> (String, Vec<String>) {
+    let mut openapi_path = String::new();
+    let mut names: Vec<String> = vec![];
+    for segment in path_segments(path) {
+        let name = match segment.strip_prefix(':') {
+            Some(name) => Some(name),
+            None => segment.strip_prefix('*'),
+        };
+        match name {
+            Some(name) => {
+                let name = if name.is_empty() { "wildcard" } else { name };
+                openapi_path.push_str(&format!("/{{{}}}", name));
+                names.push(name.to_string());
+            },
+            None => {
+                openapi_path.push('/');
+                openapi_path.push_str(segment);
+            },
+        }
+    }
+    if openapi_path.is_empty() {
+        openapi_path.push('/');
+    }
+    return (openapi_path, names);
+}
+
+/// Builds an OpenAPI 3 skeleton for every route registered on `router`.
+/// Routes without response schemas are documented with a bare `200`.
+pub fn openapi_document(router: &Router, title: &str, version: &str) -> Value {
+    let mut paths = Map::new();
+    for entry in router.routes.entries() {
+        let (path, names) = openapi_path(&entry.path);
+        let mut operation = Map::new();
+        if let Some(name) = &entry.meta.name {
+            operation.insert("operationId".to_string(), json!(name));
+        }
+        if let Some(summary) = &entry.meta.summary {
+            operation.insert("summary".to_string(), json!(summary));
+        }
+        if !entry.meta.tags.is_empty() {
+            operation.insert("tags".to_string(), json!(entry.meta.tags));
+        }
+        if !names.is_empty() {
+            let parameters: Vec<Value> = names.iter().map(|name| json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })).collect();
+            operation.insert("parameters".to_string(), json!(parameters));
+        }
+        if let Some(schema) = &entry.meta.request_schema {
+            operation.insert("requestBody".to_string(), json!({
+                "content": { "application/json": { "schema": schema } },
+            }));
+        }
+        let mut responses = Map::new();
+        for (status, schema) in &entry.meta.response_schemas {
+            responses.insert(status.to_string(), json!({
+                "description": format!("{} response", status),
+                "content": { "application/json": { "schema": schema } },
+            }));
+        }
+        if responses.is_empty() {
+            responses.insert("200".to_string(), json!({ "description": "OK" }));
+        }
+        operation.insert("responses".to_string(), Value::Object(responses));
+        let item = paths.entry(path).or_insert_with(|| json!({}));
+        if let Value::Object(item) = item {
+            item.insert(entry.method.to_lowercase(), Value::Object(operation));
+        }
+    }
+    return json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": title,
+            "version": version,
+        },
+        "paths": paths,
+    });
+}
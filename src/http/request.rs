@@ -7,6 +7,8 @@ use crate::http::response::{PotentialResponse, Response};
 
 use super::cookie::{Cookie, CookieJar};
 use super::logger::{Logger, Logs};
+use super::framing::find_header_end;
+use super::tree::decode_url_part;
 
 #[derive(Debug, Clone)]
 pub enum HttpMethod {
@@ -15,6 +17,8 @@ pub enum HttpMethod {
     PUT,
     DELETE,
     PATCH,
+    HEAD,
+    OPTIONS,
 }
 
 impl HttpMethod {
@@ -25,6 +29,8 @@ impl HttpMethod {
             HttpMethod::PUT => "PUT",
             HttpMethod::DELETE => "DELETE",
             HttpMethod::PATCH => "PATCH",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::OPTIONS => "OPTIONS",
         }
     }
 }
@@ -37,7 +43,22 @@ pub trait Contextable: Send + Sync + 'static {
     fn key(&self) -> &'static str;
 } 
 
-pub type RequestBuffer = [u8; 1024];
+/// Context keys the library sets itself.
+pub enum HttpContext {
+    /// Why a request was rejected, for example the parse error handed to `Router::bad_request`.
+    Error,
+}
+
+impl Contextable for HttpContext {
+    fn key(&self) -> &'static str {
+        match self {
+            HttpContext::Error => "ZEKE_ERROR",
+        }
+    }
+}
+
+/// The raw bytes of one complete request: the head and exactly its body.
+pub type RequestBuffer = Vec<u8>;
 
 #[derive(Debug, Clone)]
 pub struct Request {
@@ -49,6 +70,8 @@ pub struct Request {
     pub body: String,
     pub headers: Headers,
     pub params: Params,
+    /// The part of the path captured by a trailing catch-all like `/static/*path`.
+    pub wildcard: String,
     pub context: Context,
     pub cookies: CookieJar,
 }
@@ -64,6 +87,7 @@ impl Request {
             body: "".to_string(),
             headers: DashMap::new(),
             params: DashMap::new(),
+            wildcard: "".to_string(),
             context: DashMap::new(),
             cookies: CookieJar::new(),
         };
@@ -96,6 +120,35 @@ impl Request {
         }
     }
 
+    /// Looks a header up regardless of the case it was sent in, with surrounding whitespace removed.
+    pub fn get_header_ignore_case(&self, key: &str) -> String {
+        for header in self.headers.iter() {
+            if header.key().eq_ignore_ascii_case(key) {
+                return header.value().trim().to_string();
+            }
+        }
+        return "".to_string();
+    }
+
+    /// The ID of the last Server-Sent Event a reconnecting client received, so the stream can resume after it.
+    pub fn last_event_id(&self) -> Option<String> {
+        let id = self.get_header_ignore_case("Last-Event-ID");
+        if id.is_empty() {
+            return None;
+        }
+        return Some(id);
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to keep-alive and HTTP/1.0 defaults to close, unless a `Connection` header says otherwise.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.get_header_ignore_case("Connection").to_lowercase();
+        if self.protocol == "HTTP/1.0" {
+            return connection.contains("keep-alive");
+        }
+        return !connection.contains("close");
+    }
+
     pub fn header(self, key: &str, value: &str) -> Self {
         self.headers.insert(key.to_string(), value.to_string());
         self
@@ -194,6 +247,10 @@ impl Request {
         let stream = TcpStream::connect(&self.get_host());
         match stream {
             Ok(mut stream) => {
+                // the response is read until the server hangs up, so don't ask it to keep the connection
+                if self.get_header_ignore_case("Connection").is_empty() {
+                    self.headers.insert("Connection".to_string(), "close".to_string());
+                }
                 let request = self.raw();
                 match stream.write_all(request.as_bytes()) {
                     Ok(_bytes_wrote) => {
@@ -245,12 +302,14 @@ impl Request {
             headers: DashMap::new(),
             context: DashMap::new(),
             params: DashMap::new(),
+            wildcard: "".to_string(),
             cookies: CookieJar::new(),
         };
-		// TODO: investagate this line
-        let end = request_bytes.iter().position(|&x| x == 0).unwrap_or(request_bytes.len());
-        let request_string = String::from_utf8(request_bytes[..end].to_vec());
-		if let Err(request_string) = request_string {
+		// everything after the blank line that ends the headers is the body
+		let header_end = find_header_end(&request_bytes).unwrap_or(request_bytes.len());
+		let request_string = String::from_utf8(request_bytes[..header_end].to_vec());
+		let body_string = String::from_utf8(request_bytes[header_end..].to_vec());
+		if request_string.is_err() || body_string.is_err() {
 			let err = "failed to parse request";
 			return (request, Some(Response::new()
 				.status(400)
@@ -258,6 +317,7 @@ impl Request {
 			));
 		}
 		let request_string = request_string.unwrap();
+		request.body = body_string.unwrap();
 		let lines: Vec<&str> = request_string.lines().collect();
 		for i in 0..lines.len() {
 			let line = lines[i];
@@ -292,19 +352,21 @@ impl Request {
 					if parts.len() != 2 {
 						continue
 					}
-					let key = parts[0];
-					let value = parts[1];
-					request.params.insert(key.to_string(), value.to_string());
+					// query strings may encode spaces as `+`, as HTML forms do
+					let key = decode_url_part(&parts[0].replace('+', " "));
+					let value = decode_url_part(&parts[1].replace('+', " "));
+					request.params.insert(key, value);
 				}
 				// EXTRACTING PROTOCOL
 				// TODO: figure out how to handle other protocols
 				let protocol = parts[2];
 				match protocol {
 					"HTTP/1.1" => {},
+					"HTTP/1.0" => {},
 					_ => {
 						return (request, Some(Response::new()
 							.status(400)
-							.body("protocol is missing or invalid: only HTTP/1.1 is supported")
+							.body("protocol is missing or invalid: only HTTP/1.1 and HTTP/1.0 are supported")
 						));
 					},
 				}
@@ -325,6 +387,12 @@ impl Request {
 					"PATCH" => {
 						request.method = HttpMethod::PATCH;
 					},
+					"HEAD" => {
+						request.method = HttpMethod::HEAD;
+					},
+					"OPTIONS" => {
+						request.method = HttpMethod::OPTIONS;
+					},
 					_ => {
 						return (request, Some(Response::new()
 							.status(400)
@@ -335,18 +403,12 @@ impl Request {
 				request.protocol = protocol.to_string();
 				continue
 			}
-			// LAST LINE
-			// request body
-			if i == lines.len() - 1 {
-				request.body = line.to_string();
-				continue
-			}
 			// EMPTY LINES
 			if line.len() == 0 {
 				continue
 			}
 			// HEADERS
-			// ANY LINE THAT IS NOT THE FIRST OR LAST IS A HEADER
+			// ANY LINE AFTER THE FIRST IS A HEADER
 			if line.contains(":") == false {
 				return (request, Some(Response::new()
 					.status(400)
@@ -360,7 +422,7 @@ impl Request {
 			let key = parts[0];
 			let value = parts[1];
             // ANY HEADER THAT IS NOT A COOKIE
-			if key != "Cookie" {
+			if !key.eq_ignore_ascii_case("Cookie") {
 				request.headers.insert(key.to_string(), value.to_string());
 				continue
 			}
@@ -371,8 +433,8 @@ impl Request {
                 if parts.len() != 2 {
                     continue
                 }
-                let key = parts[0];
-                let value = parts[1];
+                let key = parts[0].trim();
+                let value = parts[1].trim();
                 request.cookies.add(Cookie::new(key, value));
             }
 		}
@@ -1,13 +1,90 @@
+use std::fmt;
+use std::io;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use dashmap::DashMap;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, Stream, StreamExt};
 
 
 use crate::http::cookie::{CookieJar, Cookie};
+use crate::http::socket::Connection;
+use crate::http::sse::{event_stream, with_keep_alive, Event};
 
 
 
 pub type PotentialResponse = Option<Response>;
 pub type ResponseBytes = Vec<u8>;
 pub type ResponseHeaders = DashMap<String, String>;
+pub type BodyChunk = Result<Vec<u8>, io::Error>;
+pub type BodyStream = BoxStream<'static, BodyChunk>;
+
+/// A response body produced over time. Clones of a `Response` share the same stream, which is taken once when it is written.
+#[derive(Clone)]
+pub struct ResponseStream {
+    pub inner: Arc<Mutex<Option<BodyStream>>>,
+}
+
+impl ResponseStream {
+    pub fn new<S>(stream: S) -> ResponseStream
+    where
+        S: Stream<Item = BodyChunk> + Send + 'static,
+    {
+        ResponseStream {
+            inner: Arc::new(Mutex::new(Some(stream.boxed()))),
+        }
+    }
+    pub fn take(&self) -> Option<BodyStream> {
+        match self.inner.lock() {
+            Ok(mut stream) => stream.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for ResponseStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseStream")
+    }
+}
+
+/// A connection handed over after a `101 Switching Protocols` response, starting with anything the client sent after its request.
+pub type Upgraded = Box<dyn Connection>;
+pub type UpgradeFunc = Box<dyn FnOnce(Upgraded) -> BoxFuture<'static, ()> + Send>;
+
+/// Takes over the connection once a `101 Switching Protocols` response has been written. Like `ResponseStream`,
+/// clones of a `Response` share it, and it runs once.
+#[derive(Clone)]
+pub struct ResponseUpgrade {
+    pub inner: Arc<Mutex<Option<UpgradeFunc>>>,
+}
+
+impl ResponseUpgrade {
+    pub fn new<F, Fut>(func: F) -> ResponseUpgrade
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let func: UpgradeFunc = Box::new(move |upgraded| func(upgraded).boxed());
+        ResponseUpgrade {
+            inner: Arc::new(Mutex::new(Some(func))),
+        }
+    }
+    pub fn take(&self) -> Option<UpgradeFunc> {
+        match self.inner.lock() {
+            Ok(mut func) => func.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for ResponseUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseUpgrade")
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Response {
@@ -16,6 +93,10 @@ pub struct Response {
     pub body: String,
     pub headers: ResponseHeaders,
     pub cookies: CookieJar,
+    /// When set, the body is streamed from here instead of sent from `body`.
+    pub stream: Option<ResponseStream>,
+    /// When set on a 101 response, the connection is handed to it after the response is written.
+    pub upgrade: Option<ResponseUpgrade>,
 }
 
 impl Response {
@@ -26,6 +107,8 @@ impl Response {
             body: "".to_string(),
             headers: DashMap::new(),
             cookies: CookieJar::new(),
+            stream: None,
+            upgrade: None,
         };
         return res;
     }
@@ -56,6 +139,59 @@ impl Response {
 		self.headers.insert("Content-Length".to_string(), body.len().to_string());
         return self;
     }
+    /// Streams the body from `stream`, writing each chunk to the client as it is produced.
+    /// Without a `Content-Length` header the body is sent with `Transfer-Encoding: chunked`.
+    pub fn stream<S>(mut self, stream: S) -> Self
+    where
+        S: Stream<Item = BodyChunk> + Send + 'static,
+    {
+        self.stream = Some(ResponseStream::new(stream));
+        return self;
+    }
+    /// A `text/event-stream` response sending each event as it is produced, for as long as the stream runs.
+    pub fn sse<S>(events: S) -> Response
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        let mut response = Response::new()
+            .set_header("Content-Type", "text/event-stream")
+            .set_header("Cache-Control", "no-cache");
+        response.stream = Some(ResponseStream::new(event_stream(events)));
+        return response;
+    }
+    /// Sends a keep-alive comment on a streamed body whenever it has been quiet for `interval`, so proxies don't
+    /// close an idle event stream. A zero `interval` turns it off.
+    pub fn sse_keep_alive(mut self, interval: Duration) -> Self {
+        if let Some(body) = self.stream.as_ref().and_then(|stream| stream.take()) {
+            self.stream = Some(ResponseStream::new(with_keep_alive(body, interval)));
+        }
+        return self;
+    }
+    /// Switches the connection to another protocol: the response goes out as `101 Switching Protocols`,
+    /// then `func` is given the connection and serves it for as long as it likes.
+    pub fn upgrade<F, Fut>(mut self, func: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.status = 101;
+        self.upgrade = Some(ResponseUpgrade::new(func));
+        return self;
+    }
+    pub fn has_length(&self) -> bool {
+        return self.headers.iter().any(|header| header.key().eq_ignore_ascii_case("Content-Length"));
+    }
+    pub fn has_transfer_encoding(&self) -> bool {
+        return self.headers.iter().any(|header| header.key().eq_ignore_ascii_case("Transfer-Encoding"));
+    }
+    /// Whether the body will be sent with chunked framing: a streamed body of unknown length to a client that understands it.
+    pub fn is_chunked(&self) -> bool {
+        return self.stream.is_some() && !self.has_length() && self.protocol != "HTTP/1.0";
+    }
+    /// Whether the client can tell where this response ends without the connection being closed.
+    pub fn is_framed(&self) -> bool {
+        return self.stream.is_none() || self.has_length() || self.is_chunked();
+    }
     pub fn new_from_bytes(response_bytes: &Vec<u8>) -> Response {
         let mut response = Response::new();
         let end = response_bytes.iter().position(|&x| x == 0).unwrap_or(response_bytes.len());
@@ -150,12 +286,22 @@ impl Response {
         for cookie in &self.cookies.cookies {
             header_string.push_str(&format!("Set-Cookie: {}\r\n", cookie.to_string()));
         }
+        // without a length the client can only find the end of the body when the connection closes,
+        // which breaks keep-alive, so every response that may carry a body gets one
+        let has_body = self.status >= 200 && self.status != 204 && self.status != 304;
+        if self.is_chunked() && has_body {
+            header_string.push_str("Transfer-Encoding: chunked\r\n");
+        } else if !self.has_length() && !self.has_transfer_encoding() && self.stream.is_none() && has_body {
+            header_string.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        // a streamed body is written after the head, chunk by chunk
+        let body = if self.stream.is_some() { "" } else { self.body.as_str() };
         // Now create the full response with status line, headers, and body
         let full_response = format!(
             "HTTP/1.1 {}\r\n{}\r\n{}",
             self.status, 
             header_string,
-            self.body
+            body
         );
         full_response.into_bytes() // Convert the full response string to bytes
     }
@@ -207,5 +353,7 @@ pub fn not_found() -> Response {
         body: "Not Found".to_string(),
         headers: DashMap::new(),
         cookies: CookieJar::new(),
+        stream: None,
+        upgrade: None,
     }
 }
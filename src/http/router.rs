@@ -1,46 +1,455 @@
 use std::sync::Arc;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
 
-use tokio::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use serde::Serialize;
+use serde_json::Value;
 
 
 use crate::http::middleware::{Middlewares, Middleware, MiddlewareGroup};
 use crate::http::handler::Handler;
-use crate::http::socket::connect_socket;
-
-use dashmap::DashMap;
+use crate::http::openapi::openapi_document;
+use crate::http::socket::{bind_listener, connect_socket, is_connection_error, Listener, ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF, MAX_SHEDDING};
+#[cfg(unix)]
+use crate::http::socket::bind_unix_listener;
+use crate::http::shutdown::drain_connections;
+use crate::http::config::{ServerConfig, MAX_CONNECTIONS_LIMIT};
+use crate::http::tls::tls_acceptor;
+use crate::http::server::ServerHandle;
+use crate::http::tree::{path_segments, split_pattern, RouteError, RouteTree};
 
 pub type RouteHandler = (Handler, Middlewares, Middlewares);
 
-pub type Routes = DashMap<&'static str, Arc<Mutex<RouteHandler>>>;
+pub type Routes = RouteTree;
+
+/// Optional documentation attached to a `Route`, used by `Router::describe` and `Router::openapi`.
+#[derive(Debug, Clone, Default)]
+pub struct RouteMeta {
+    pub name: Option<String>,
+    pub summary: Option<String>,
+    pub tags: Vec<String>,
+    pub request_schema: Option<Value>,
+    pub response_schemas: Vec<(u16, Value)>,
+}
+
+/// A registered route as reported by `Router::describe`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub method: String,
+    pub path: String,
+    pub middlewares: usize,
+    pub outerwares: usize,
+    pub name: Option<String>,
+    pub summary: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Why `Router::url_for` could not build a path.
+#[derive(Debug, Clone)]
+pub enum UrlError {
+    /// No route was registered with this name.
+    UnknownRoute(String),
+    /// The route's pattern needs a parameter that was not given, as `(route name, parameter)`.
+    MissingParam(String, String),
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UrlError::UnknownRoute(name) => write!(f, "no route is named `{}`", name),
+            UrlError::MissingParam(name, param) => write!(f, "route `{}` needs a value for `{}`", name, param),
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+/// Percent-encodes everything but unreserved characters, so a value is safe inside one path segment or query pair.
+fn encode_url_part(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    return encoded;
+}
 
 
 pub struct Router {
     pub routes: Routes,
+    pub fallback: Option<Arc<RouteHandler>>,
+    pub bad_request: Option<Arc<RouteHandler>>,
+    pub middlewares: Middlewares,
+    pub outerwares: Middlewares,
+    /// Route names mapped to the path pattern they were registered with.
+    pub names: HashMap<String, String>,
+    /// Timeouts, limits and socket options used when serving.
+    pub config: ServerConfig,
 }
 
 impl Router {
     pub fn new() -> Router {
         Router {
-            routes: DashMap::new(),
+            routes: RouteTree::new(),
+            fallback: None,
+            bad_request: None,
+            middlewares: vec![],
+            outerwares: vec![],
+            names: HashMap::new(),
+            config: ServerConfig::new(),
         }
     }
+    /// Registers a route. Patterns may contain named segments like `GET /users/:id`,
+    /// whose matched values are inserted into `Request.params`.
+    /// Panics if the route is malformed or conflicts with one already registered; use `try_add` to handle that instead.
     pub fn add(self: &mut Router, route: Route) -> &mut Router {
+        let result = self.try_add(route);
+        if let Err(err) = result {
+            panic!("{}", err);
+        }
+        return self;
+    }
+    /// Registers a route, returning an error if it is malformed, registered twice,
+    /// or disagrees with an existing pattern at the same segment.
+    pub fn try_add(self: &mut Router, route: Route) -> Result<&mut Router, RouteError> {
         let handler: RouteHandler = (route.handler, route.middlewares, route.outerwares);
-        let handler_mutex = Arc::new(Mutex::new(handler));
-        self.routes.insert(route.path, handler_mutex);
+        self.register(route.path, Arc::new(handler), route.meta)?;
+        return Ok(self);
+    }
+    fn register(self: &mut Router, pattern: &str, handler: Arc<RouteHandler>, meta: RouteMeta) -> Result<(), RouteError> {
+        let name = meta.name.clone();
+        if let Some(name) = &name {
+            if self.names.contains_key(name) {
+                return Err(RouteError::DuplicateName(name.to_string()));
+            }
+        }
+        self.routes.insert(pattern, handler, meta)?;
+        if let Some(name) = name {
+            let (_, path) = split_pattern(pattern);
+            self.names.insert(name, path.to_string());
+        }
+        return Ok(());
+    }
+    /// Mounts every route of `router` under `prefix`, so `GET /users` nested at `/api/v1` becomes `GET /api/v1/users`.
+    /// Router-wide middleware and outerware set on `router` are carried over to each of its routes.
+    /// Panics if a mounted route conflicts with one already registered; use `try_nest` to handle that instead.
+    pub fn nest(self: &mut Router, prefix: &str, router: Router) -> &mut Router {
+        if let Err(err) = self.try_nest(prefix, router) {
+            panic!("{}", err);
+        }
+        return self;
+    }
+    /// Mounts every route of `router` under `prefix`, returning an error if one conflicts with a route already registered.
+    /// Nothing is mounted unless every route can be. A nested router can't bring its own `fallback` or
+    /// `bad_request` handler, since those apply to the whole server; set them on the outer router instead.
+    pub fn try_nest(self: &mut Router, prefix: &str, router: Router) -> Result<&mut Router, RouteError> {
+        let prefix = prefix.trim_end_matches('/');
+        if router.fallback.is_some() || router.bad_request.is_some() {
+            return Err(RouteError::Invalid(format!("{}: a nested router can't have its own fallback or bad_request handler", prefix)));
+        }
+        // a conflict partway through puts these back, so the router is left as it was
+        let routes = self.routes.clone();
+        let names = self.names.clone();
+        for entry in router.routes.entries() {
+            let (handler, route_middlewares, route_outerwares) = &*entry.handler;
+            let mut middlewares = router.middlewares.clone();
+            middlewares.extend(route_middlewares.iter().cloned());
+            let mut outerwares = route_outerwares.clone();
+            outerwares.extend(router.outerwares.iter().cloned());
+            let route_handler: RouteHandler = (handler.clone(), middlewares, outerwares);
+            let pattern = format!("{} {}{}", entry.method, prefix, entry.path);
+            if let Err(err) = self.register(&pattern, Arc::new(route_handler), entry.meta) {
+                self.routes = routes;
+                self.names = names;
+                return Err(err);
+            }
+        }
+        return Ok(self);
+    }
+    /// Like `nest`, but also attaches `middleware_group` to every mounted route.
+    /// The group's middleware runs before each route's own middleware, and its outerware runs after the route's outerware.
+    pub fn nest_group(self: &mut Router, prefix: &str, router: Router, middleware_group: MiddlewareGroup) -> &mut Router {
+        if let Err(err) = self.try_nest_group(prefix, router, middleware_group) {
+            panic!("{}", err);
+        }
+        return self;
+    }
+    /// Like `try_nest`, but also attaches `middleware_group` to every mounted route.
+    pub fn try_nest_group(self: &mut Router, prefix: &str, mut router: Router, middleware_group: MiddlewareGroup) -> Result<&mut Router, RouteError> {
+        let mut middlewares = middleware_group.middlewares;
+        middlewares.append(&mut router.middlewares);
+        router.middlewares = middlewares;
+        router.outerwares.extend(middleware_group.outerwares);
+        return self.try_nest(prefix, router);
+    }
+    /// Adds middleware that runs for every request, before any route-level middleware.
+    /// It applies to routes added later too, as well as the fallback and the built-in 404 and 405 responses.
+    pub fn middleware(self: &mut Router, middleware: Middleware) -> &mut Router {
+        self.middlewares.push(middleware);
+        return self;
+    }
+    /// Adds outerware that runs for every request, after any route-level outerware.
+    pub fn outerware(self: &mut Router, outerware: Middleware) -> &mut Router {
+        self.outerwares.push(outerware);
+        return self;
+    }
+    /// Applies a `MiddlewareGroup` to every request, as `middleware` and `outerware` would.
+    pub fn group(self: &mut Router, middleware_group: MiddlewareGroup) -> &mut Router {
+        for middleware in middleware_group.middlewares {
+            self.middlewares.push(middleware);
+        }
+        for outerware in middleware_group.outerwares {
+            self.outerwares.push(outerware);
+        }
+        return self;
+    }
+    /// Replaces the timeouts, limits and socket options used when serving.
+    pub fn config(self: &mut Router, config: ServerConfig) -> &mut Router {
+        self.config = config;
+        return self;
+    }
+    /// Limits the request line plus headers to `bytes`. Larger requests are answered with 431.
+    pub fn max_header_size(self: &mut Router, bytes: usize) -> &mut Router {
+        self.config.max_header_size = bytes;
+        return self;
+    }
+    /// Limits request bodies to `bytes`. Larger bodies are answered with 413 before they are read.
+    pub fn max_body_size(self: &mut Router, bytes: usize) -> &mut Router {
+        self.config.max_body_size = bytes;
+        return self;
+    }
+    /// Sets the handler used when no route matches the request path.
+    /// The handler chooses the status, so remember to set a 404 on the response.
+    pub fn fallback(self: &mut Router, handler: Handler) -> &mut Router {
+        return self.fallback_group(handler, MiddlewareGroup::new(vec![], vec![]));
+    }
+    /// Like `fallback`, but runs the handler behind `middleware_group` so unmatched requests can still be traced and logged.
+    pub fn fallback_group(self: &mut Router, handler: Handler, middleware_group: MiddlewareGroup) -> &mut Router {
+        let handler: RouteHandler = (handler, middleware_group.middlewares, middleware_group.outerwares);
+        self.fallback = Some(Arc::new(handler));
+        return self;
+    }
+    /// Sets the handler used when a request cannot be parsed.
+    /// The parse error is available with `request.get_context(HttpContext::Error)`.
+    pub fn bad_request(self: &mut Router, handler: Handler) -> &mut Router {
+        return self.bad_request_group(handler, MiddlewareGroup::new(vec![], vec![]));
+    }
+    /// Like `bad_request`, but runs the handler behind `middleware_group`.
+    pub fn bad_request_group(self: &mut Router, handler: Handler, middleware_group: MiddlewareGroup) -> &mut Router {
+        let handler: RouteHandler = (handler, middleware_group.middlewares, middleware_group.outerwares);
+        self.bad_request = Some(Arc::new(handler));
         return self;
     }
+    /// Builds the path for the route registered with `.name(name)`, filling its parameters from `params`.
+    /// A catch-all takes its value from the parameter of the same name (or `*` when unnamed) and may be left out.
+    /// Params the pattern doesn't use are appended as a query string.
+    pub fn url_for(self: &Router, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        let pattern = self.names.get(name);
+        if pattern.is_none() {
+            return Err(UrlError::UnknownRoute(name.to_string()));
+        }
+        let pattern = pattern.unwrap();
+        let mut used: Vec<&str> = vec![];
+        let mut url = String::new();
+        for segment in path_segments(pattern) {
+            if let Some(param) = segment.strip_prefix(':') {
+                let value = params.iter().find(|(key, _)| *key == param);
+                if value.is_none() {
+                    return Err(UrlError::MissingParam(name.to_string(), param.to_string()));
+                }
+                used.push(param);
+                url.push('/');
+                url.push_str(&encode_url_part(value.unwrap().1));
+                continue;
+            }
+            if let Some(param) = segment.strip_prefix('*') {
+                let param = if param.is_empty() { "*" } else { param };
+                used.push(param);
+                if let Some((_, value)) = params.iter().find(|(key, _)| *key == param) {
+                    let value = value.trim_matches('/');
+                    if !value.is_empty() {
+                        // a catch-all spans segments, so only the pieces between slashes are encoded
+                        let parts: Vec<String> = value.split('/').map(encode_url_part).collect();
+                        url.push('/');
+                        url.push_str(&parts.join("/"));
+                    }
+                }
+                continue;
+            }
+            url.push('/');
+            url.push_str(segment);
+        }
+        if url.is_empty() {
+            url.push('/');
+        }
+        let query: Vec<String> = params.iter()
+            .filter(|(key, _)| !used.contains(key))
+            .map(|(key, value)| format!("{}={}", encode_url_part(key), encode_url_part(value)))
+            .collect();
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        return Ok(url);
+    }
+    /// Lists every registered route with its method, pattern and route-level middleware counts,
+    /// for example to print a startup banner or serve a `/__routes` debug endpoint.
+    pub fn describe(self: &Router) -> Vec<RouteInfo> {
+        let mut infos: Vec<RouteInfo> = vec![];
+        for entry in self.routes.entries() {
+            infos.push(RouteInfo {
+                method: entry.method,
+                path: entry.path,
+                middlewares: entry.handler.1.len(),
+                outerwares: entry.handler.2.len(),
+                name: entry.meta.name,
+                summary: entry.meta.summary,
+                tags: entry.meta.tags,
+            });
+        }
+        return infos;
+    }
+    /// Builds an OpenAPI 3 document describing every registered route.
+    pub fn openapi(self: &Router, title: &str, version: &str) -> Value {
+        return openapi_document(self, title, version);
+    }
+    /// Binds `addr` and serves forever. The router is frozen into an `Arc`-shared, immutable
+    /// route table here, so requests to the same route run in parallel without any locking.
     pub async fn serve(self: Router, addr: &str) -> Result<(), Error> {
-		let listener = tokio::net::TcpListener::bind(&addr).await;
+        return self.serve_with_shutdown(addr, std::future::pending()).await;
+    }
+    /// Like `serve`, using `config` for timeouts, limits and socket options.
+    pub async fn serve_with(mut self: Router, addr: &str, config: ServerConfig) -> Result<(), Error> {
+        self.config = config;
+        return self.serve(addr).await;
+    }
+    /// Like `serve`, but stops accepting connections once `signal` completes, gives in-flight requests
+    /// up to `ServerConfig.shutdown_timeout` to finish, and then returns. Idle keep-alive connections are closed right away.
+    /// Pass `shutdown_signal()` to shut down on Ctrl+C or SIGTERM.
+    pub async fn serve_with_shutdown<F>(self: Router, addr: &str, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+		let listener = bind_listener(addr, &self.config).await;
 		if listener.is_err() {
 			return Result::Err(listener.err().unwrap());
 		}
-		let listener = listener.unwrap();
+		return self.run(Listener::Tcp(listener.unwrap()), None, signal).await;
+    }
+    /// Like `serve`, but on a listener that is already bound, such as one bound to `127.0.0.1:0` to get a free port.
+    pub async fn serve_listener(self: Router, listener: TcpListener) -> Result<(), Error> {
+        return self.run(Listener::Tcp(listener), None, std::future::pending()).await;
+    }
+    /// Binds `addr` and serves in a background task, returning as soon as the server is listening.
+    /// The returned handle reports the bound address and shuts the server down.
+    pub async fn spawn(self: Router, addr: &str) -> Result<ServerHandle, Error> {
+        let listener = bind_listener(addr, &self.config).await?;
+        return self.spawn_listener(listener);
+    }
+    /// Like `spawn`, but on a listener that is already bound.
+    pub fn spawn_listener(self: Router, listener: TcpListener) -> Result<ServerHandle, Error> {
+        let local_addr = listener.local_addr()?;
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let task = tokio::spawn(self.run(Listener::Tcp(listener), None, async move {
+            // a dropped handle shuts the server down as well
+            let _ = signal.await;
+        }));
+        return Ok(ServerHandle::new(local_addr, shutdown, task));
+    }
+    /// Like `serve`, but listens on a Unix domain socket at `path`. A stale socket file left by a previous run is replaced,
+    /// the file gets `ServerConfig.unix_socket_mode` permissions, and it is removed again once the server stops.
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Router, path: &str) -> Result<(), Error> {
+        return self.serve_unix_with_shutdown(path, std::future::pending()).await;
+    }
+    /// Like `serve_unix`, but shuts down gracefully once `signal` completes, as `serve_with_shutdown` does.
+    #[cfg(unix)]
+    pub async fn serve_unix_with_shutdown<F>(self: Router, path: &str, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+		let listener = bind_unix_listener(Path::new(path), &self.config).await?;
+		let result = self.run(Listener::Unix(listener), None, signal).await;
+		let _ = std::fs::remove_file(path);
+		return result;
+    }
+    /// Like `serve`, but over HTTPS, using the PEM certificate chain and private key at the given paths.
+    /// The files are checked for changes on every handshake, so a renewed certificate is picked up without a restart.
+    pub async fn serve_tls(self: Router, addr: &str, cert_path: &str, key_path: &str) -> Result<(), Error> {
+        return self.serve_tls_with_shutdown(addr, cert_path, key_path, std::future::pending()).await;
+    }
+    /// Like `serve_tls`, but shuts down gracefully once `signal` completes, as `serve_with_shutdown` does.
+    pub async fn serve_tls_with_shutdown<F>(self: Router, addr: &str, cert_path: &str, key_path: &str, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+		let acceptor = tls_acceptor(Path::new(cert_path), Path::new(key_path))?;
+		let listener = bind_listener(addr, &self.config).await?;
+		return self.run(Listener::Tcp(listener), Some(acceptor), signal).await;
+    }
+    /// Accepts connections on `listener` until `signal` completes, then drains them.
+    async fn run<F>(self: Router, listener: Listener, tls: Option<TlsAcceptor>, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
 		let router: Arc<Router> = Arc::new(self);
+		let (shutdown, shutdown_receiver) = watch::channel(false);
+		let mut connections: JoinSet<()> = JoinSet::new();
+		let max_connections = router.config.max_connections;
+		if max_connections == 0 || max_connections > MAX_CONNECTIONS_LIMIT {
+			// the field can be set directly, past the checks in `ServerConfig`
+			return Err(Error::new(ErrorKind::InvalidInput, format!("max_connections must be between 1 and {}, not {}", MAX_CONNECTIONS_LIMIT, max_connections)));
+		}
+		let limit = Arc::new(Semaphore::new(max_connections));
+		let shedding = Arc::new(Semaphore::new(MAX_SHEDDING));
+		let mut backoff = Duration::ZERO;
+		tokio::pin!(signal);
 		loop {
-			connect_socket(&listener, Arc::clone(&router)).await; 
+			// reap finished connections so the set only holds live ones
+			while connections.try_join_next().is_some() {}
+			if !backoff.is_zero() {
+				// accepting keeps failing, most likely for lack of file descriptors, so give connections time to close
+				tokio::select! {
+					_ = &mut signal => {
+						break;
+					},
+					_ = tokio::time::sleep(backoff) => {},
+				}
+			}
+			tokio::select! {
+				_ = &mut signal => {
+					break;
+				},
+				result = connect_socket(&listener, Arc::clone(&router), &mut connections, shutdown_receiver.clone(), tls.clone(), &limit, &shedding) => {
+					match result {
+						Ok(()) => {
+							backoff = Duration::ZERO;
+						},
+						Err(err) if is_connection_error(&err) => {},
+						Err(_) => {
+							// TODO: log
+							backoff = (backoff * 2).clamp(ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+						},
+					}
+				},
+			}
 		}
+		drop(listener);
+		let _ = shutdown.send(true);
+		drain_connections(connections, router.config.shutdown_timeout).await;
+		return Ok(());
     }
 }
 
@@ -49,6 +458,7 @@ pub struct Route {
     pub handler: Handler,
     pub middlewares: Middlewares,
     pub outerwares: Middlewares,
+    pub meta: RouteMeta,
 }
 
 impl Route {
@@ -58,6 +468,7 @@ impl Route {
             handler: handler,
             middlewares: vec![],
             outerwares: vec![],
+            meta: RouteMeta::default(),
         };
         return route;
     }
@@ -69,6 +480,30 @@ impl Route {
         self.outerwares.push(outerware);
         return self;
     }
+    /// Names the route so its path can be built with `Router::url_for`.
+    pub fn name(mut self: Route, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        return self;
+    }
+    /// A one-line description of the route, used as the OpenAPI operation summary.
+    pub fn summary(mut self: Route, summary: &str) -> Self {
+        self.meta.summary = Some(summary.to_string());
+        return self;
+    }
+    pub fn tag(mut self: Route, tag: &str) -> Self {
+        self.meta.tags.push(tag.to_string());
+        return self;
+    }
+    /// The JSON schema of the request body.
+    pub fn request_schema(mut self: Route, schema: Value) -> Self {
+        self.meta.request_schema = Some(schema);
+        return self;
+    }
+    /// The JSON schema of the response body sent with `status`.
+    pub fn response_schema(mut self: Route, status: u16, schema: Value) -> Self {
+        self.meta.response_schemas.push((status, schema));
+        return self;
+    }
     pub fn group(mut self: Route, middleware_group: MiddlewareGroup) -> Self {
         for middleware in middleware_group.middlewares {
             self.middlewares.push(middleware);
@@ -85,3 +520,98 @@ impl Route {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::Request;
+    use crate::http::response::Response;
+    use crate::http::socket::handle_request;
+
+    fn ok() -> Handler {
+        return Handler::new(|request| {
+            Box::pin(async move {
+                return (request, Response::new().body("ok"));
+            })
+        });
+    }
+
+    /// A handler answering with the `id` param and the `q` query value it received.
+    fn echo_params() -> Handler {
+        return Handler::new(|request| {
+            Box::pin(async move {
+                let id = request.params.get("id").map(|id| id.to_string()).unwrap_or_default();
+                let q = request.params.get("q").map(|q| q.to_string()).unwrap_or_default();
+                return (request, Response::new().body(&format!("{}|{}", id, q)));
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn url_for_round_trips() {
+        let mut r = Router::new();
+        r.add(Route::new("GET /users/:id", echo_params()).name("user"));
+        let url = r.url_for("user", &[("id", "a b/c%"), ("q", "x&y=z")]).unwrap();
+        assert_eq!(url, "/users/a%20b%2Fc%25?q=x%26y%3Dz");
+        let request_bytes = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", url).into_bytes();
+        let (request, potential_response) = Request::new_from_bytes(request_bytes);
+        assert!(potential_response.is_none());
+        let response = handle_request(Arc::new(r), request).await;
+        assert_eq!(response.body, "a b/c%|x&y=z");
+    }
+
+    #[test]
+    fn try_nest_reports_conflicts() {
+        let mut r = Router::new();
+        r.add(Route::new("GET /api/users/:id", ok()));
+        let mut users = Router::new();
+        users.add(Route::new("GET /users/:user_id", ok()));
+        let err = r.try_nest("/api", users).err().unwrap();
+        assert!(matches!(err, RouteError::Conflict(_)));
+    }
+
+    #[test]
+    fn try_nest_mounts_nothing_on_conflict() {
+        let mut r = Router::new();
+        r.add(Route::new("GET /api/users/:id", ok()));
+        let mut users = Router::new();
+        users.add(Route::new("GET /", ok()).name("home"));
+        users.add(Route::new("GET /status", ok()));
+        users.add(Route::new("GET /users/:user_id", ok()));
+        assert!(r.try_nest("/api", users).is_err());
+        assert!(r.routes.get("GET", "/api").is_none());
+        assert!(r.routes.get("GET", "/api/status").is_none());
+        assert!(r.names.get("home").is_none());
+        assert!(r.routes.get("GET", "/api/users/42").is_some());
+    }
+
+    #[test]
+    fn try_nest_rejects_nested_fallback() {
+        let mut r = Router::new();
+        let mut users = Router::new();
+        users.add(Route::new("GET /users", ok()));
+        users.fallback(ok());
+        let err = r.try_nest("/api", users).err().unwrap();
+        assert!(matches!(err, RouteError::Invalid(_)));
+    }
+
+    #[test]
+    fn try_nest_group_mounts_under_prefix() {
+        let mut r = Router::new();
+        let mut users = Router::new();
+        users.add(Route::new("GET /users/:id", ok()));
+        r.try_nest_group("/api/v1/", users, MiddlewareGroup::new(vec![], vec![])).unwrap();
+        assert!(r.routes.get("GET", "/api/v1/users/42").is_some());
+    }
+
+    #[tokio::test]
+    async fn serve_rejects_out_of_range_max_connections() {
+        for count in [0, usize::MAX] {
+            let mut r = Router::new();
+            r.config.max_connections = count;
+            let server = r.spawn("127.0.0.1:0").await.unwrap();
+            let err = server.shutdown().await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
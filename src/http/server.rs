@@ -0,0 +1,39 @@
+use std::io::Error;
+use std::net::SocketAddr;
+
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// A server running in the background, as started by `Router::spawn`.
+/// Dropping the handle shuts the server down too, so a test can't leave one running by accident.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(local_addr: SocketAddr, shutdown: oneshot::Sender<()>, task: JoinHandle<Result<(), Error>>) -> ServerHandle {
+        return ServerHandle {
+            local_addr,
+            shutdown,
+            task,
+        };
+    }
+    /// The address the server is listening on, with the real port when it was bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.local_addr;
+    }
+    /// Shuts the server down gracefully and waits for it to stop, as `Router::serve_with_shutdown` does.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let _ = self.shutdown.send(());
+        match self.task.await {
+            Ok(result) => {
+                return result;
+            },
+            Err(err) => {
+                return Err(Error::other(err));
+            },
+        }
+    }
+}
@@ -1,90 +1,598 @@
 use std::time::Duration;
-use std::sync::{Arc, PoisonError};
+use std::sync::Arc;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::io::{Error, ErrorKind, IoSlice};
+#[cfg(unix)]
+use std::{fs, path::Path, os::unix::fs::{FileTypeExt, PermissionsExt}};
 
-use tokio::sync::RwLock;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, time::timeout, sync::MutexGuard};
+use futures::StreamExt;
 
-use crate::http::router::Router;
+use socket2::{SockRef, TcpKeepalive};
+
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf}, net::{lookup_host, TcpListener, TcpSocket, TcpStream}, time::timeout};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use crate::http::router::{Router, RouteHandler};
 use crate::http::middleware::Middlewares;
 use crate::http::response::{Response, ResponseBytes, PotentialResponse};
-use crate::http::request::{Request, RequestBuffer};
-use crate::http::handler::Handler;
+use crate::http::request::{HttpContext, HttpMethod, Request, RequestBuffer};
+use crate::http::framing::{expects_continue, find_header_end, BodyFraming, RequestHead};
+use crate::http::shutdown::wait_for_shutdown;
+use crate::http::config::{OverloadPolicy, ServerConfig};
+use crate::http::http2::{h2c_upgrade, is_h2c_upgrade, read_preface, serve_http2};
 
-pub async fn connect_socket(listener: &TcpListener, router: Arc<Router>) {
-	let socket_result = listener.accept().await;
-	if socket_result.is_err() {
-		// TODO: log
-		return;
-	}
-	let socket_result = socket_result.unwrap();
-	let (socket, _addr) = socket_result;
-	tokio::spawn(async move {
-		let (socket, response) = handle_connection(socket, router).await;
-		let response_bytes: ResponseBytes = response.to_bytes();
-		let (mut socket, err_response) = write_socket(socket, &response_bytes).await;
-		if err_response.is_some() {
-			// TODO: log
+/// Binds `addr` with the listen backlog from `config`. Address reuse is enabled so a restarted server can bind straight away.
+pub async fn bind_listener(addr: &str, config: &ServerConfig) -> Result<TcpListener, Error> {
+	let mut last_err = Error::new(ErrorKind::InvalidInput, format!("could not resolve {}", addr));
+	for socket_addr in lookup_host(addr).await? {
+		let socket = if socket_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+		#[cfg(unix)]
+		socket.set_reuseaddr(true)?;
+		if let Err(err) = socket.bind(socket_addr) {
+			last_err = err;
+			continue;
 		}
-		let shutdown_result = socket.shutdown().await;
-		if shutdown_result.is_err() {
-			// TODO: log
+		return socket.listen(config.backlog);
+	}
+	return Err(last_err);
+}
+
+/// Applies the socket options from `config` to an accepted connection.
+pub fn configure_stream(socket: &TcpStream, config: &ServerConfig) -> Result<(), Error> {
+	socket.set_nodelay(config.tcp_nodelay)?;
+	if let Some(idle) = config.tcp_keepalive {
+		let keepalive = TcpKeepalive::new().with_time(idle);
+		SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+	}
+	return Ok(());
+}
+
+/// Anything a connection can be served over, such as a `TcpStream` or a TLS stream wrapping one.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
+/// A connection with bytes that were already read from it put back in front, so whatever reads it next sees them first.
+pub struct Rewind<S> {
+	prefix: Vec<u8>,
+	position: usize,
+	inner: S,
+}
+
+impl<S> Rewind<S> {
+	pub fn new(prefix: Vec<u8>, inner: S) -> Rewind<S> {
+		Rewind {
+			prefix,
+			position: 0,
+			inner,
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+		let rewind = self.get_mut();
+		if rewind.position < rewind.prefix.len() {
+			let length = buf.remaining().min(rewind.prefix.len() - rewind.position);
+			buf.put_slice(&rewind.prefix[rewind.position..rewind.position + length]);
+			rewind.position += length;
+			return Poll::Ready(Ok(()));
+		}
+		return Pin::new(&mut rewind.inner).poll_read(cx, buf);
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+		return Pin::new(&mut self.get_mut().inner).poll_write(cx, buf);
+	}
+	fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, Error>> {
+		return Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs);
+	}
+	fn is_write_vectored(&self) -> bool {
+		return self.inner.is_write_vectored();
+	}
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		return Pin::new(&mut self.get_mut().inner).poll_flush(cx);
+	}
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		return Pin::new(&mut self.get_mut().inner).poll_shutdown(cx);
+	}
+}
+
+/// A bound socket the server accepts connections from.
+pub enum Listener {
+	Tcp(TcpListener),
+	#[cfg(unix)]
+	Unix(UnixListener),
+}
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket file left behind by a server that is no longer running,
+/// and applies `config.unix_socket_mode` to it. Refuses to replace a socket another server is still listening on, or anything that isn't a socket.
+#[cfg(unix)]
+pub async fn bind_unix_listener(path: &Path, config: &ServerConfig) -> Result<UnixListener, Error> {
+	if let Ok(metadata) = fs::symlink_metadata(path) {
+		if !metadata.file_type().is_socket() {
+			return Err(Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+		}
+		if UnixStream::connect(path).await.is_ok() {
+			return Err(Error::new(ErrorKind::AddrInUse, format!("{} is already being served", path.display())));
+		}
+		fs::remove_file(path)?;
+	}
+	let listener = UnixListener::bind(path)?;
+	if let Some(mode) = config.unix_socket_mode {
+		fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+	}
+	return Ok(listener);
+}
+
+/// The first delay after an accept error that isn't about a single connection, such as running out of file descriptors.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+/// The longest delay between accept attempts while they keep failing.
+pub const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether an accept error only affects the connection being accepted, so accepting the next one can go ahead straight away.
+pub fn is_connection_error(err: &Error) -> bool {
+	return matches!(err.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted);
+}
+
+/// Accepts one connection and spawns its task onto `connections`, so the server can wait for it when shutting down.
+/// Each connection holds a permit from `limit` while it is open. When none are left, this waits for one before accepting,
+/// or with `OverloadPolicy::Shed`, accepts the new connection anyway just to answer it with a 503. Shed connections hold
+/// a permit from `shedding`, and while those are all taken too, this waits for one to free up as with `Pause`.
+pub async fn connect_socket(listener: &Listener, router: Arc<Router>, connections: &mut JoinSet<()>, shutdown: watch::Receiver<bool>, tls: Option<TlsAcceptor>, limit: &Arc<Semaphore>, shedding: &Arc<Semaphore>) -> Result<(), Error> {
+	let mut permit: Option<OwnedSemaphorePermit> = None;
+	if router.config.overload_policy == OverloadPolicy::Pause {
+		match Arc::clone(limit).acquire_owned().await {
+			Ok(acquired) => {
+				permit = Some(acquired);
+			},
+			Err(_) => {
+				return Ok(());
+			},
+		}
+	} else if limit.available_permits() == 0 && shedding.available_permits() == 0 {
+		// too many connections are being shed already, so wait for room to either serve or shed another
+		tokio::select! {
+			acquired = Arc::clone(limit).acquire_owned() => {
+				permit = acquired.ok();
+			},
+			_ = shedding.acquire() => {},
+		}
+	}
+	match listener {
+		Listener::Tcp(listener) => {
+			let (socket, _addr) = listener.accept().await?;
+			let permit = match permit.or_else(|| Arc::clone(limit).try_acquire_owned().ok()) {
+				Some(permit) => permit,
+				None => {
+					shed(socket, connections, shedding);
+					return Ok(());
+				},
+			};
+			if configure_stream(&socket, &router.config).is_err() {
+				// TODO: log
+			}
+			spawn_connection(socket, router, connections, shutdown, tls, permit);
+		},
+		#[cfg(unix)]
+		Listener::Unix(listener) => {
+			let (socket, _addr) = listener.accept().await?;
+			let permit = match permit.or_else(|| Arc::clone(limit).try_acquire_owned().ok()) {
+				Some(permit) => permit,
+				None => {
+					shed(socket, connections, shedding);
+					return Ok(());
+				},
+			};
+			spawn_connection(socket, router, connections, shutdown, tls, permit);
+		},
+	}
+	return Ok(());
+}
+
+/// How long a shed connection is given to take its 503 before it is dropped.
+pub const SHED_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many shed connections may be waiting on their 503 at once.
+pub const MAX_SHEDDING: usize = 64;
+
+/// Answers `socket` with `shed_connection` if a permit from `shedding` is free, and otherwise just closes it.
+fn shed<S: Connection>(socket: S, connections: &mut JoinSet<()>, shedding: &Arc<Semaphore>) {
+	if let Ok(permit) = Arc::clone(shedding).try_acquire_owned() {
+		connections.spawn(async move {
+			shed_connection(socket).await;
+			drop(permit);
+		});
+	}
+}
+
+/// Answers a connection the server has no room for with a 503 and closes it, without reading the request.
+/// Whatever the client sent is drained for a moment afterwards, since closing with unread data would reset the
+/// connection and could lose the 503 before the client reads it.
+pub async fn shed_connection<S: Connection>(mut socket: S) {
+	let response_bytes = Response::new()
+		.status(503)
+		.set_header("Connection", "close")
+		.set_header("Retry-After", "1")
+		.body("server is at capacity")
+		.to_bytes();
+	let _ = timeout(SHED_TIMEOUT, async {
+		socket.write_all(&response_bytes).await?;
+		socket.shutdown().await?;
+		let mut discard: [u8; 1024] = [0; 1024];
+		while socket.read(&mut discard).await? > 0 {}
+		return Ok::<(), Error>(());
+	}).await;
+}
+
+/// Spawns the task serving `socket` onto `connections`. `permit` is released when the connection closes.
+/// With `tls`, the handshake also happens in that task, so a slow client can't hold up accepting others.
+pub fn spawn_connection<S: Connection>(socket: S, router: Arc<Router>, connections: &mut JoinSet<()>, shutdown: watch::Receiver<bool>, tls: Option<TlsAcceptor>, permit: OwnedSemaphorePermit) {
+	connections.spawn(async move {
+		let _permit = permit;
+		let acceptor = match tls {
+			Some(acceptor) => acceptor,
+			None => {
+				handle_connection(socket, router, shutdown).await;
+				return;
+			},
+		};
+		match timeout(router.config.read_timeout, acceptor.accept(socket)).await {
+			Ok(Ok(stream)) => {
+				handle_connection(stream, router, shutdown).await;
+			},
+			_ => {
+				// TODO: log the failed handshake
+			},
 		}
-		return;
 	});
+}
 
+/// Serves requests on one connection until the client asks to close, goes idle for
+/// `keep_alive_timeout`, `max_requests_per_connection` requests have been answered, or the server shuts down.
+/// A shutdown never interrupts a request that has started arriving; it is answered with `Connection: close`.
+/// With `config.http2`, a client that opens with the HTTP/2 preface is served HTTP/2 instead.
+pub async fn handle_connection<S: Connection>(mut socket: S, router: Arc<Router>, mut shutdown: watch::Receiver<bool>) {
+	let config = &router.config;
+	let mut served: usize = 0;
+	// bytes read from the connection but not yet parsed, which may hold pipelined requests
+	let mut buffer: RequestBuffer = Vec::with_capacity(4096);
+	loop {
+		let mut potential_response: PotentialResponse = None;
+		if buffer.is_empty() {
+			let wait = if served == 0 { config.read_timeout } else { config.keep_alive_timeout };
+			// wait for the next request to start arriving, unless the server shuts down first
+			let first_read = tokio::select! {
+				result = timeout(wait, socket.read_buf(&mut buffer)) => result,
+				_ = wait_for_shutdown(&mut shutdown) => {
+					break;
+				},
+			};
+			match first_read {
+				Ok(Ok(bytes_read)) if bytes_read > 0 => {},
+				Ok(_) => {
+					// the client closed the connection, or it failed, before sending anything
+					break;
+				},
+				Err(_) if served > 0 => {
+					// the client went idle between requests
+					break;
+				},
+				Err(_) => {
+					potential_response = Some(Response::new()
+						.status(408)
+						.body("read timeout")
+					);
+				},
+			}
+		}
+		// HTTP/2 with prior knowledge
+		if served == 0 && potential_response.is_none() && config.http2 && read_preface(&mut socket, &mut buffer, config).await {
+			serve_http2(Rewind::new(buffer, socket), router, shutdown).await;
+			return;
+		}
+		// requests are answered one at a time, so pipelined responses always go out in the order the requests came in
+		let (next_socket, response, keep_alive) = match potential_response {
+			None => serve_request(socket, &mut buffer, &router, &shutdown).await,
+			Some(response) => (socket, response, false),
+		};
+		socket = next_socket;
+		served += 1;
+		let upgrade = if response.status == 101 { response.upgrade.as_ref().and_then(|upgrade| upgrade.take()) } else { None };
+		// a streamed body of unknown length can only be ended by closing the connection
+		let keep_alive = keep_alive && served < config.max_requests_per_connection && response.is_framed() && !*shutdown.borrow();
+		if upgrade.is_some() {
+			response.headers.insert("Connection".to_string(), "Upgrade".to_string());
+		} else if keep_alive {
+			response.headers.insert("Connection".to_string(), "keep-alive".to_string());
+		} else {
+			response.headers.insert("Connection".to_string(), "close".to_string());
+		}
+		let (next_socket, err_response) = write_response(socket, response, &mut buffer, config, &mut shutdown).await;
+		socket = next_socket;
+		if err_response.is_some() {
+			// TODO: log
+			break;
+		}
+		if let Some(upgrade) = upgrade {
+			// the connection belongs to the new protocol now, along with anything the client already sent for it
+			upgrade(Box::new(Rewind::new(buffer, socket))).await;
+			return;
+		}
+		if !keep_alive {
+			break;
+		}
+	}
+	let shutdown_result = socket.shutdown().await;
+	if shutdown_result.is_err() {
+		// TODO: log
+	}
 }
 
-pub async fn handle_connection(socket: TcpStream, router: Arc<Router>) -> (TcpStream, Response) {
-    let (socket, request_bytes, potetial_response) = read_socket(socket).await;
-	if potetial_response.is_some() {
-		return (socket, potetial_response.unwrap());
+/// Reads and answers the next request on a connection, also reporting whether the connection may be kept open afterwards.
+/// The connection is always closed after an error reading the request, since whatever follows it can't be trusted.
+/// A request without a body asking for `Upgrade: h2c` is answered with a 101 that hands the connection to HTTP/2.
+pub async fn serve_request<S: Connection>(socket: S, buffer: &mut RequestBuffer, router: &Arc<Router>, shutdown: &watch::Receiver<bool>) -> (S, Response, bool) {
+	let config = &router.config;
+	let (socket, head) = read_head(socket, buffer, config).await;
+	let head = match head {
+		Ok(head) => head,
+		Err(response) => {
+			let head_end = find_header_end(buffer).unwrap_or(buffer.len());
+			return (socket, reject_request(router, &buffer[..head_end], response).await, false);
+		},
+	};
+	if config.http2 && !head.has_body() && is_h2c_upgrade(&buffer[..head.header_end]) {
+		let head_bytes: RequestBuffer = buffer.drain(..head.header_end).collect();
+		return (socket, h2c_upgrade(head_bytes, Arc::clone(router), shutdown.clone()), false);
 	}
-	if request_bytes.len() == 0 {
-		// TODO: does it matter if we get any bytes?
-		return (socket, Response::new().status(200));
+	match expects_continue(&buffer[..head.header_end]) {
+		// a client that sent its body without waiting needs no interim response
+		Ok(true) if head.has_body() && buffer.len() == head.header_end => {
+			return handle_expect_continue(socket, buffer, head, router).await;
+		},
+		Ok(_) => {},
+		Err(err) => {
+			return (socket, reject_request(router, &buffer[..head.header_end], err.response()).await, false);
+		},
+	}
+	let header_end = head.header_end;
+	let (socket, request_bytes, potential_response) = read_body(socket, buffer, head, config).await;
+	if let Some(response) = potential_response {
+		return (socket, reject_request(router, &buffer[..header_end], response).await, false);
 	}
-	let (request, potential_response) = Request::new_from_bytes(request_bytes);
+	let (response, keep_alive) = handle_request_bytes(router, request_bytes).await;
+	return (socket, response, keep_alive);
+}
+
+/// Answers a request sent with `Expect: 100-continue`. The head is routed and run through the middleware in front of its
+/// handler first, so a request that would be turned away anyway, say by auth middleware or a 404, is answered before the
+/// client uploads anything. Otherwise `100 Continue` is sent, the body is read, and the request carries on to its handler.
+pub async fn handle_expect_continue<S: Connection>(socket: S, buffer: &mut RequestBuffer, head: RequestHead, router: &Arc<Router>) -> (S, Response, bool) {
+	let config = &router.config;
+	let head_bytes = buffer[..head.header_end].to_vec();
+	let (request, potential_response) = Request::new_from_bytes(head_bytes.clone());
 	if potential_response.is_some() {
-		return (socket, potential_response.unwrap());
+		let (response, _) = handle_request_bytes(router, head_bytes).await;
+		return (socket, response, false);
 	}
-	let response: Response = handle_request(router, request).await;
-	return (socket, response);
+	let keep_alive = request.keep_alive();
+	let protocol = request.protocol.clone();
+	let (mut request, dispatch) = dispatch_request(router, request).await;
+	let route_handler = match dispatch {
+		Dispatch::Handler(route_handler) => route_handler,
+		dispatch => {
+			// the body was never sent, so the connection can't be reused without the client's next bytes being misread
+			let response = finish_request(router, request, dispatch).await;
+			return (socket, response, false);
+		},
+	};
+	let (socket, err_response) = write_socket(socket, b"HTTP/1.1 100 Continue\r\n\r\n", config.write_timeout).await;
+	if let Some(response) = err_response {
+		return (socket, response, false);
+	}
+	let (socket, request_bytes, potential_response) = read_body(socket, buffer, head, config).await;
+	if let Some(response) = potential_response {
+		return (socket, reject_request(router, &head_bytes, response).await, false);
+	}
+	let (full_request, potential_response) = Request::new_from_bytes(request_bytes);
+	if let Some(response) = potential_response {
+		return (socket, response, false);
+	}
+	request.body = full_request.body;
+	// trailers sent after a chunked body join the headers, but like in `ChunkedDecoder::request_bytes` never replace one
+	for header in full_request.headers.iter() {
+		if !request.headers.contains_key(header.key()) {
+			request.headers.insert(header.key().to_string(), header.value().to_string());
+		}
+	}
+	let mut response = finish_request(router, request, Dispatch::Handler(route_handler)).await;
+	if protocol == "HTTP/1.0" {
+		response.protocol = protocol;
+	}
+	return (socket, response, keep_alive);
+}
+
+/// Answers a request whose framing couldn't be read. A 400 goes to `router.bad_request` when one is set, with the
+/// reason as `HttpContext::Error` and whatever could be parsed from `head`, as `handle_request_bytes` does for
+/// requests that fail to parse. Other statuses, like 413 or 431, are sent as they are.
+pub async fn reject_request(router: &Arc<Router>, head: &[u8], response: Response) -> Response {
+	if response.status != 400 {
+		return response;
+	}
+	if let Some(bad_request) = &router.bad_request {
+		let (mut request, _) = Request::new_from_bytes(head.to_vec());
+		request.set_context(HttpContext::Error, response.body.clone());
+		let (_, response) = handle_route(Arc::clone(bad_request), request).await;
+		return response;
+	}
+	return response;
 }
 
+/// Parses and answers a single request, also reporting whether the connection may be kept open afterwards.
+pub async fn handle_request_bytes(router: &Arc<Router>, request_bytes: RequestBuffer) -> (Response, bool) {
+	let (mut request, potential_response) = Request::new_from_bytes(request_bytes);
+	if let Some(response) = potential_response {
+		// the framing of anything after a malformed request can't be trusted, so always close
+		if let Some(bad_request) = &router.bad_request {
+			request.set_context(HttpContext::Error, response.body.clone());
+			let (_, response) = handle_route(Arc::clone(bad_request), request).await;
+			return (response, false);
+		}
+		return (response, false);
+	}
+	let keep_alive = request.keep_alive();
+	let protocol = request.protocol.clone();
+	let mut response: Response = handle_request(Arc::clone(router), request).await;
+	if protocol == "HTTP/1.0" {
+		// HTTP/1.0 clients don't understand chunked bodies
+		response.protocol = protocol;
+	}
+	return (response, keep_alive);
+}
+
+/// How far a request got through the middleware in front of its handler.
+pub enum Dispatch {
+	/// All of the middleware passed, so the handler is next.
+	Handler(Arc<RouteHandler>),
+	/// Settled before the handler by route middleware, or by the built-in 404, 405 and OPTIONS answers. Router outerware still runs.
+	Response(Response),
+	/// Router middleware short-circuited, so this goes out as it is.
+	Done(Response),
+}
+
+/// Routes a request and runs it through the full chain, in this order:
+/// router middleware, route middleware, handler, route outerware, router outerware.
+/// Router-wide middleware also wraps the fallback and the built-in 404, 405 and OPTIONS responses.
 pub async fn handle_request(router: Arc<Router>, request: Request) -> Response {
-    let route_handler = router.routes.get(request.method_and_path.as_str());
-	if route_handler.is_none() {
-		return Response::new()
+	let (request, dispatch) = dispatch_request(&router, request).await;
+	return finish_request(&router, request, dispatch).await;
+}
+
+/// Routes a request and runs the part of the chain in front of its handler: router middleware, then route middleware.
+pub async fn dispatch_request(router: &Arc<Router>, mut request: Request) -> (Request, Dispatch) {
+	let is_head = matches!(request.method, HttpMethod::HEAD);
+    let mut route_handler = router.routes.get(request.method.as_str(), &request.path);
+	// HEAD falls back to the GET handler when no HEAD route was registered
+	if route_handler.is_none() && is_head {
+		route_handler = router.routes.get(HttpMethod::GET.as_str(), &request.path);
+	}
+	if let Some(route_match) = &route_handler {
+		for (key, value) in &route_match.params {
+			request.params.insert(key.to_string(), value.to_string());
+		}
+		if let Some(wildcard) = &route_match.wildcard {
+			request.wildcard = wildcard.to_string();
+		}
+	}
+	let (request, potential_response) = handle_middleware(request, &router.middlewares).await;
+	if let Some(response) = potential_response {
+		return (request, Dispatch::Done(response));
+	}
+	match route_handler {
+		Some(route_match) => {
+			return dispatch_route(route_match.handler, request).await;
+		},
+		None => {
+			return dispatch_unmatched(router, request).await;
+		},
+	}
+}
+
+/// Runs a dispatched request the rest of the way: the handler and route outerware, then router outerware.
+pub async fn finish_request(router: &Arc<Router>, request: Request, dispatch: Dispatch) -> Response {
+	let is_head = matches!(request.method, HttpMethod::HEAD);
+	let (request, mut response) = match dispatch {
+		Dispatch::Done(response) => {
+			return response;
+		},
+		Dispatch::Response(response) => (request, response),
+		Dispatch::Handler(route_handler) => handle_handler(route_handler, request).await,
+	};
+	let (_, potential_response) = handle_middleware(request, &router.outerwares).await;
+	if let Some(outerware_response) = potential_response {
+		response = outerware_response;
+	}
+	if is_head {
+		// keep the headers (including Content-Length) but never send a body
+		response.body = "".to_string();
+		if response.is_chunked() {
+			// the stream is dropped, but the head should still say how a GET would be framed
+			response.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+		}
+		response.stream = None;
+	}
+	return response;
+}
+
+/// Dispatches a request no route matched: OPTIONS gets the allowed methods, a known path with the
+/// wrong method gets a 405, and anything else goes to the fallback handler or a plain 404.
+pub async fn dispatch_unmatched(router: &Arc<Router>, request: Request) -> (Request, Dispatch) {
+	let allowed = router.routes.allowed(&request.path);
+	if allowed.is_empty() {
+		if let Some(fallback) = &router.fallback {
+			return dispatch_route(Arc::clone(fallback), request).await;
+		}
+		return (request, Dispatch::Response(Response::new()
 			.status(404)
-			.body("route not found");
+			.body("route not found")));
 	}
-	let route_handler = route_handler.unwrap();
-	let potential_route: Result<MutexGuard<(Handler, Middlewares, Middlewares)>, PoisonError<MutexGuard<(Handler, Middlewares, Middlewares)>>> = Ok(route_handler.lock().await); // TODO: need to handle this ok() better
-	if potential_route.is_err() {
-		return Response::new()
-			.status(500)
-			.body("failed to lock route handler")
+	let allow = allowed.join(", ");
+	if matches!(request.method, HttpMethod::OPTIONS) {
+		return (request, Dispatch::Response(Response::new()
+			.status(204)
+			.set_header("Allow", &allow)));
 	}
-	let route_handler = potential_route.unwrap();
-	let (handler, middlewares, outerwares) = &*route_handler;
+	return (request, Dispatch::Response(Response::new()
+		.status(405)
+		.set_header("Allow", &allow)
+		.body("method not allowed")));
+}
+
+/// Runs a route's middleware, dispatching to its handler if all of it passes.
+pub async fn dispatch_route(route_handler: Arc<RouteHandler>, request: Request) -> (Request, Dispatch) {
+	let (_, middlewares, _) = &*route_handler;
 	let (request, potential_response) = handle_middleware(request, middlewares).await;
 	match potential_response {
 		Some(response) => {
-			return response;
+			return (request, Dispatch::Response(response));
 		},
 		None => {
-			let handler = handler.func.read().await;
-			let (request, handler_response) = handler(request).await;
-			// TODO: clean all the white space up out of the handler_response?
-			let (_, potential_response) = handle_middleware(request, outerwares).await;
-			match potential_response {
-				Some(response) => {
-					return response;
-				},
-				None => {
-					return handler_response;
-				},
-			}
+			return (request, Dispatch::Handler(route_handler));
+		},
+	}
+}
+
+pub async fn handle_route(route_handler: Arc<RouteHandler>, request: Request) -> (Request, Response) {
+	let (request, dispatch) = dispatch_route(route_handler, request).await;
+	match dispatch {
+		Dispatch::Handler(route_handler) => {
+			return handle_handler(route_handler, request).await;
+		},
+		Dispatch::Response(response) | Dispatch::Done(response) => {
+			return (request, response);
+		},
+	}
+}
+
+/// Runs a route's handler and then its outerware.
+pub async fn handle_handler(route_handler: Arc<RouteHandler>, request: Request) -> (Request, Response) {
+	let (handler, _, outerwares) = &*route_handler;
+	let (request, handler_response) = (handler.func)(request).await;
+	// TODO: clean all the white space up out of the handler_response?
+	let (request, potential_response) = handle_middleware(request, outerwares).await;
+	match potential_response {
+		Some(response) => {
+			return (request, response);
+		},
+		None => {
+			return (request, handler_response);
 		},
 	}
 }
@@ -94,8 +602,7 @@ pub async fn handle_middleware(mut request: Request, middlewares: &Middlewares)
         return (request, None);
     };
     for middleware in middlewares {
-        let middleware = middleware.func.read().await;
-        let potential_response = middleware(&mut request).await;
+        let potential_response = (middleware.func)(&mut request).await;
 		if potential_response.is_none() {
 			continue;
 		}
@@ -105,39 +612,191 @@ pub async fn handle_middleware(mut request: Request, middlewares: &Middlewares)
     return (request, None);
 }
 
-pub async fn read_socket(mut socket: TcpStream) -> (TcpStream, RequestBuffer, PotentialResponse) {
-    let mut buffer: [u8; 1024] = [0; 1024];
-    match timeout(Duration::from_secs(5), socket.read(&mut buffer)).await {
+/// Reads until `buffer`, which holds whatever has already been read from the connection, contains a complete request head,
+/// then works out how the body after it is framed. A head over `config.max_header_size` is answered with 431,
+/// and a declared body over `config.max_body_size` with 413. Every read may take up to `config.read_timeout`.
+pub async fn read_head<S: AsyncRead + Unpin>(mut socket: S, buffer: &mut RequestBuffer, config: &ServerConfig) -> (S, Result<RequestHead, Response>) {
+    loop {
+        let header_end = find_header_end(buffer);
+        let head_size = header_end.unwrap_or(buffer.len());
+        if head_size > config.max_header_size {
+            return (socket, Err(Response::new()
+                .status(431)
+                .body("request headers too large")
+            ));
+        }
+        if let Some(header_end) = header_end {
+            let head = RequestHead::new(&buffer[..header_end], config.max_header_size, config.max_body_size);
+            return (socket, head.map_err(|err| err.response()));
+        }
+        if let Err(response) = read_more(&mut socket, buffer, config).await {
+            return (socket, Err(response));
+        }
+    }
+}
+
+/// Reads the body framed by `head`: exactly `Content-Length` bytes, or a chunked body which is decoded (trailers included)
+/// into a plain one. Returns the whole request, taking its bytes out of `buffer` and leaving anything after them,
+/// such as the next pipelined request, in place. A chunked body over `config.max_body_size` is answered with 413.
+pub async fn read_body<S: AsyncRead + Unpin>(mut socket: S, buffer: &mut RequestBuffer, mut head: RequestHead, config: &ServerConfig) -> (S, RequestBuffer, PotentialResponse) {
+    let header_end = head.header_end;
+    loop {
+        match &mut head.body {
+            BodyFraming::Chunked(decoder) => {
+                match decoder.decode(&buffer[header_end..]) {
+                    Ok(true) => {
+                        let request_bytes = decoder.request_bytes(&buffer[..header_end]);
+                        buffer.drain(..header_end + decoder.position);
+                        return (socket, request_bytes, None);
+                    },
+                    Ok(false) => {},
+                    Err(err) => {
+                        return (socket, vec![], Some(err.response()));
+                    },
+                }
+            },
+            BodyFraming::Length(body_length) => {
+                if buffer.len() >= header_end + *body_length {
+                    let request_bytes: RequestBuffer = buffer.drain(..header_end + *body_length).collect();
+                    return (socket, request_bytes, None);
+                }
+            },
+        }
+        if let Err(response) = read_more(&mut socket, buffer, config).await {
+            return (socket, vec![], Some(response));
+        }
+    }
+}
+
+/// Reads whatever the client sends next onto the end of `buffer`, waiting up to `config.read_timeout` for it.
+pub async fn read_more<S: AsyncRead + Unpin>(socket: &mut S, buffer: &mut RequestBuffer, config: &ServerConfig) -> Result<(), Response> {
+    let mut chunk: [u8; 4096] = [0; 4096];
+    match timeout(config.read_timeout, socket.read(&mut chunk)).await {
         Ok(Ok(bytes_read)) if bytes_read > 0 => {
-            // TODO: trunacate() and keep only what was read?
-            return (socket, buffer, None);
+            buffer.extend_from_slice(&chunk[..bytes_read]);
+            return Ok(());
         },
-        Ok(Ok(_)) => {
+        Ok(Ok(_)) if buffer.is_empty() => {
             // No data read, potentially a graceful close
-            return (socket, buffer, Some(Response::new()
+            return Err(Response::new()
                 .status(500)
                 .body("no data received from client connection")
-            ));
+            );
+        },
+        Ok(Ok(_)) => {
+            return Err(Response::new()
+                .status(400)
+                .body("malformed request: connection closed before the request was complete")
+            );
         },
         Ok(Err(e)) => {
             // Handle specific I/O errors if needed
-            return (socket, buffer, Some(Response::new()
+            return Err(Response::new()
                 .status(500)
                 .body(&format!("failed to read from socket: {}", e))
-            ));
+            );
         },
         Err(_) => {
             // Timeout
-            return (socket, buffer, Some(Response::new()
+            return Err(Response::new()
                 .status(408)
                 .body("read timeout")
-            ));
+            );
         },
     }
 }
 
-pub async fn write_socket(mut socket: TcpStream, response_bytes: &[u8]) -> (TcpStream, PotentialResponse) {
-    match timeout(Duration::from_secs(5), socket.write_all(response_bytes)).await {
+/// Writes a response, streaming its body when it has one. Each chunk is written and flushed to the socket before the
+/// next one is pulled from the stream, so a slow client slows the producer down instead of filling memory.
+/// Each write may take up to `config.write_timeout`.
+/// While waiting on the stream, the connection is watched too, so a client that goes away is noticed even when nothing
+/// is being written, and so is the server shutting down. Either way the stream is dropped and the body is ended there.
+/// Bytes the client sends meanwhile, such as its next request, are kept in `buffer`.
+pub async fn write_response<S: Connection>(socket: S, response: Response, buffer: &mut RequestBuffer, config: &ServerConfig, shutdown: &mut watch::Receiver<bool>) -> (S, PotentialResponse) {
+	let write_timeout = config.write_timeout;
+	let response_bytes: ResponseBytes = response.to_bytes();
+	let stream = response.stream.as_ref().and_then(|stream| stream.take());
+	let (mut socket, err_response) = write_socket(socket, &response_bytes, write_timeout).await;
+	if err_response.is_some() || stream.is_none() {
+		return (socket, err_response);
+	}
+	let mut stream = stream.unwrap();
+	let chunked = response.is_chunked();
+	let mut incoming: [u8; 1024] = [0; 1024];
+	loop {
+		// stop reading once a request's worth is waiting, rather than buffer whatever the client sends
+		let watching = buffer.len() < config.max_header_size;
+		let chunk = tokio::select! {
+			chunk = stream.next() => chunk,
+			read = socket.read(&mut incoming), if watching => {
+				match read {
+					Ok(bytes_read) if bytes_read > 0 => {
+						buffer.extend_from_slice(&incoming[..bytes_read]);
+						continue;
+					},
+					_ => {
+						// the client went away, so stop producing a body nobody will read
+						drop(stream);
+						if chunked {
+							let (next_socket, _) = write_socket(socket, b"0\r\n\r\n", write_timeout).await;
+							socket = next_socket;
+						}
+						return (socket, Some(Response::new()
+							.status(500)
+							.body("client closed the connection during a streamed response")
+						));
+					},
+				}
+			},
+			_ = wait_for_shutdown(shutdown) => None,
+		};
+		let chunk = match chunk {
+			Some(chunk) => chunk,
+			None => {
+				break;
+			},
+		};
+		if let Err(e) = chunk {
+			// the head is already out, so all we can do is cut the body short
+			return (socket, Some(Response::new()
+				.status(500)
+				.body(&format!("response stream failed: {}", e))
+			));
+		}
+		let chunk = chunk.unwrap();
+		if chunk.is_empty() {
+			// an empty chunk would end a chunked body early
+			continue;
+		}
+		let mut chunk_bytes: ResponseBytes = vec![];
+		if chunked {
+			chunk_bytes.extend_from_slice(format!("{:X}\r\n", chunk.len()).as_bytes());
+			chunk_bytes.extend_from_slice(&chunk);
+			chunk_bytes.extend_from_slice(b"\r\n");
+		} else {
+			chunk_bytes = chunk;
+		}
+		let (next_socket, err_response) = write_socket(socket, &chunk_bytes, write_timeout).await;
+		socket = next_socket;
+		if err_response.is_some() {
+			return (socket, err_response);
+		}
+	}
+	drop(stream);
+	if chunked {
+		return write_socket(socket, b"0\r\n\r\n", write_timeout).await;
+	}
+	return (socket, None);
+}
+
+/// Writes `response_bytes` and flushes them, so nothing sits in a buffer in front of the socket, such as a TLS session's,
+/// until the next write. That matters for a streamed body, where the next write may be a long time coming.
+pub async fn write_socket<S: AsyncWrite + Unpin>(mut socket: S, response_bytes: &[u8], write_timeout: Duration) -> (S, PotentialResponse) {
+    let write = async {
+        socket.write_all(response_bytes).await?;
+        return socket.flush().await;
+    };
+    match timeout(write_timeout, write).await {
         Ok(Ok(_)) => {
             return (socket, None);
         },
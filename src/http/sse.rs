@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use futures::stream::{self, Stream, StreamExt};
+use tokio::time::{interval_at, Instant};
+
+use crate::http::response::{BodyChunk, BodyStream};
+
+/// The comment sent to keep an idle event stream open.
+const KEEP_ALIVE_COMMENT: &[u8] = b": keep-alive\n\n";
+
+/// One Server-Sent Event, as sent in a `Response::sse` stream.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: Option<String>,
+    pub retry: Option<Duration>,
+    pub comment: Option<String>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: Some(data.to_string()),
+            ..Event::default()
+        }
+    }
+    /// A comment the client ignores, which can be used to keep a connection from looking idle.
+    pub fn comment(comment: &str) -> Event {
+        Event {
+            comment: Some(comment.to_string()),
+            ..Event::default()
+        }
+    }
+    /// The event type, which browsers dispatch to `addEventListener(name)` instead of `onmessage`.
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(name.to_string());
+        return self;
+    }
+    /// The ID the client sends back as `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        return self;
+    }
+    /// How long the client should wait before reconnecting if the connection drops.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        return self;
+    }
+    /// Encodes the event in the `text/event-stream` format. Multi-line data and comments are sent as one line each
+    /// per line, and line breaks are dropped from the other fields so they can't end the event early.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut frame = String::new();
+        if let Some(comment) = &self.comment {
+            for line in split_lines(comment) {
+                frame.push_str(&format!(": {}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = &self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            for line in split_lines(data) {
+                if line.is_empty() {
+                    frame.push_str("data:\n");
+                } else {
+                    frame.push_str(&format!("data: {}\n", line));
+                }
+            }
+        }
+        frame.push('\n');
+        return frame.into_bytes();
+    }
+}
+
+/// Splits on CRLF, a bare CR or a bare LF, which clients all treat as line breaks. Unlike `str::lines`, a trailing
+/// line break leaves an empty last line, so `"a\n"` arrives as `"a\n"` rather than `"a"`.
+fn split_lines(value: &str) -> Vec<&str> {
+    let bytes = value.as_bytes();
+    let mut lines: Vec<&str> = vec![];
+    let mut start = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'\r' || bytes[index] == b'\n' {
+            lines.push(&value[start..index]);
+            if bytes[index] == b'\r' && bytes.get(index + 1) == Some(&b'\n') {
+                index += 1;
+            }
+            start = index + 1;
+        }
+        index += 1;
+    }
+    lines.push(&value[start..]);
+    return lines;
+}
+
+fn single_line(value: &str) -> String {
+    return value.replace(['\r', '\n'], "");
+}
+
+/// Encodes a stream of events as body chunks, one event per chunk.
+pub fn event_stream<S>(events: S) -> BodyStream
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    return events.map(|event| Ok(event.to_bytes())).boxed();
+}
+
+/// Sends a keep-alive comment whenever `body` has gone `interval` without producing anything,
+/// ending when `body` does, which keeps proxies from closing an idle connection. A zero `interval` leaves `body` as it is.
+pub fn with_keep_alive(body: BodyStream, interval: Duration) -> BodyStream {
+    if interval.is_zero() {
+        return body;
+    }
+    let ticker = interval_at(Instant::now() + interval, interval);
+    return stream::unfold((body, ticker), |(mut body, mut ticker)| async move {
+        let chunk: BodyChunk = tokio::select! {
+            chunk = body.next() => {
+                ticker.reset();
+                chunk?
+            },
+            _ = ticker.tick() => Ok(KEEP_ALIVE_COMMENT.to_vec()),
+        };
+        return Some((chunk, (body, ticker)));
+    }).boxed();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(event: Event) -> String {
+        return String::from_utf8(event.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn encodes_fields() {
+        let event = Event::new("hello").event("greeting").id("7").retry(Duration::from_secs(3));
+        assert_eq!(frame(event), "event: greeting\nid: 7\nretry: 3000\ndata: hello\n\n");
+        assert_eq!(frame(Event::comment("ping")), ": ping\n\n");
+        assert_eq!(frame(Event::new("")), "data:\n\n");
+    }
+
+    #[test]
+    fn splits_data_on_every_line_break() {
+        assert_eq!(frame(Event::new("a\nb\r\nc\rd")), "data: a\ndata: b\ndata: c\ndata: d\n\n");
+        // a bare CR can't smuggle in fields of its own
+        assert_eq!(frame(Event::new("x\rid: 1\revent: admin")), "data: x\ndata: id: 1\ndata: event: admin\n\n");
+        assert_eq!(frame(Event::comment("a\rretry: 1")), ": a\n: retry: 1\n\n");
+    }
+
+    #[test]
+    fn keeps_trailing_line_breaks() {
+        assert_eq!(frame(Event::new("a\n")), "data: a\ndata:\n\n");
+        assert_eq!(frame(Event::new("\n\n")), "data:\ndata:\ndata:\n\n");
+    }
+
+    #[test]
+    fn strips_line_breaks_from_other_fields() {
+        assert_eq!(frame(Event::new("x").event("a\rb").id("1\n2")), "event: ab\nid: 12\ndata: x\n\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alive_fills_quiet_gaps() {
+        let events = stream::unfold(0, |count| async move {
+            if count == 2 {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_secs(25)).await;
+            return Some((Event::new(&count.to_string()), count + 1));
+        });
+        let chunks: Vec<Vec<u8>> = with_keep_alive(event_stream(events), Duration::from_secs(10))
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        let keep_alive = KEEP_ALIVE_COMMENT.to_vec();
+        let expected = vec![
+            keep_alive.clone(),
+            keep_alive.clone(),
+            b"data: 0\n\n".to_vec(),
+            keep_alive.clone(),
+            keep_alive.clone(),
+            b"data: 1\n\n".to_vec(),
+        ];
+        assert_eq!(chunks, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_interval_turns_keep_alive_off() {
+        let events = stream::iter(vec![Event::new("only")]);
+        let chunks: Vec<Vec<u8>> = with_keep_alive(event_stream(events), Duration::ZERO)
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec![b"data: only\n\n".to_vec()]);
+    }
+}
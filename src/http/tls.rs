@@ -0,0 +1,113 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use tokio_rustls::rustls::{self, ServerConfig as TlsConfig};
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::TlsAcceptor;
+
+/// Reads every certificate from a PEM file, leaf first.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, Error>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("no certificates found in {}", path.display())));
+    }
+    return Ok(certs);
+}
+
+/// Reads the first private key from a PEM file, whether PKCS#8, PKCS#1 or SEC1.
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => {
+            return Ok(key);
+        },
+        None => {
+            return Err(Error::new(ErrorKind::InvalidData, format!("no private key found in {}", path.display())));
+        },
+    }
+}
+
+/// Loads a certificate chain and its key into the form rustls signs handshakes with.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Error> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let signing_key = any_supported_type(&key).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    return Ok(CertifiedKey::new(certs, signing_key));
+}
+
+/// Hands rustls the certificate for each handshake, reloading it from disk whenever the certificate or key file changes.
+/// If a reload fails, say because the files are halfway through being replaced, the previous certificate keeps being used.
+pub struct CertReloader {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    current: RwLock<(Option<SystemTime>, Arc<CertifiedKey>)>,
+}
+
+impl CertReloader {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<CertReloader, Error> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        let modified = last_modified(cert_path, key_path);
+        return Ok(CertReloader {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new((modified, Arc::new(certified_key))),
+        });
+    }
+    /// The current certificate, reloaded first if the files have changed since it was loaded.
+    pub fn certified_key(&self) -> Option<Arc<CertifiedKey>> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        {
+            let current = self.current.read().ok()?;
+            if modified.is_none() || current.0 == modified {
+                return Some(Arc::clone(&current.1));
+            }
+        }
+        let mut current = self.current.write().ok()?;
+        if current.0 != modified {
+            if let Ok(certified_key) = load_certified_key(&self.cert_path, &self.key_path) {
+                *current = (modified, Arc::new(certified_key));
+            }
+        }
+        return Some(Arc::clone(&current.1));
+    }
+}
+
+impl fmt::Debug for CertReloader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CertReloader({}, {})", self.cert_path.display(), self.key_path.display())
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        return self.certified_key();
+    }
+}
+
+/// The later modification time of the two files, or `None` if either can't be read.
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert_modified = fs::metadata(cert_path).and_then(|metadata| metadata.modified()).ok()?;
+    let key_modified = fs::metadata(key_path).and_then(|metadata| metadata.modified()).ok()?;
+    return Some(cert_modified.max(key_modified));
+}
+
+/// Builds an acceptor serving the PEM certificate and key at the given paths, advertising `http/1.1` over ALPN.
+/// The files are checked for changes on every handshake, so renewed certificates are picked up without a restart.
+pub fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, Error> {
+    let reloader = CertReloader::new(cert_path, key_path)?;
+    let mut config = TlsConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err: rustls::Error| Error::other(err))?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(reloader));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    return Ok(TlsAcceptor::from(Arc::new(config)));
+}
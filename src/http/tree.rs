@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::http::router::{RouteHandler, RouteMeta};
+
+pub type RouteParams = Vec<(String, String)>;
+
+/// Why a route could not be registered.
+#[derive(Debug, Clone)]
+pub enum RouteError {
+    /// The same method and path were registered twice.
+    Duplicate(String),
+    /// Two patterns disagree at the same segment, like `/users/:id` and `/users/:user_id`.
+    Conflict(String),
+    /// The pattern itself is malformed.
+    Invalid(String),
+    /// Two routes were given the same name.
+    DuplicateName(String),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteError::Duplicate(pattern) => write!(f, "duplicate route: {} is already registered", pattern),
+            RouteError::Conflict(message) => write!(f, "conflicting route: {}", message),
+            RouteError::Invalid(message) => write!(f, "invalid route: {}", message),
+            RouteError::DuplicateName(name) => write!(f, "duplicate route name: `{}` is already used", name),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// The result of a successful lookup: the handler plus everything captured from the path.
+pub struct RouteMatch {
+    pub handler: Arc<RouteHandler>,
+    pub params: RouteParams,
+    pub wildcard: Option<String>,
+}
+
+/// Splits a route pattern like `"GET /users/:id"` into its method and path.
+pub fn split_pattern(pattern: &str) -> (&str, &str) {
+    match pattern.split_once(' ') {
+        Some((method, path)) => {
+            return (method.trim(), path.trim());
+        },
+        None => {
+            return ("", pattern.trim());
+        },
+    }
+}
+
+/// Breaks a path into its non-empty segments, so `/users/42/` and `/users/42` are the same.
+pub fn path_segments(path: &str) -> Vec<&str> {
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+/// Decodes `%XX` escapes in a captured path value. Escapes that aren't valid hex are kept as they are,
+/// and bytes that don't form valid UTF-8 are replaced.
+pub fn decode_url_part(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let is_escape = bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit();
+        if is_escape {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+            decoded.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+/// A registered route, as recovered by walking the tree.
+pub struct RouteEntry {
+    pub method: String,
+    pub path: String,
+    pub handler: Arc<RouteHandler>,
+    pub meta: RouteMeta,
+}
+
+/// A segment trie used to match request paths against route patterns.
+/// Each node owns the handlers registered for the path ending at that node, keyed by method.
+#[derive(Clone)]
+pub struct RouteNode {
+    pub segment: String,
+    pub literals: Vec<RouteNode>,
+    pub param: Option<Box<RouteNode>>,
+    pub wildcard: Option<Box<RouteNode>>,
+    pub handlers: HashMap<String, Arc<RouteHandler>>,
+    pub meta: HashMap<String, RouteMeta>,
+}
+
+impl RouteNode {
+    pub fn new(segment: &str) -> RouteNode {
+        RouteNode {
+            segment: segment.to_string(),
+            literals: vec![],
+            param: None,
+            wildcard: None,
+            handlers: HashMap::new(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// The parameter name for a `:name` or `*name` node, or `None` for literal nodes.
+    pub fn param_name(&self) -> Option<&str> {
+        if let Some(name) = self.segment.strip_prefix(':') {
+            return Some(name);
+        }
+        return self.segment.strip_prefix('*');
+    }
+
+    /// Inserts `handler` at the node for `segments`, refusing anything that would shadow or
+    /// contradict a route that is already registered. `pattern` is only used for error messages.
+    pub fn insert(&mut self, segments: &[&str], method: &str, handler: Arc<RouteHandler>, meta: RouteMeta, pattern: &str) -> Result<(), RouteError> {
+        if segments.is_empty() {
+            if self.handlers.contains_key(method) {
+                return Err(RouteError::Duplicate(pattern.to_string()));
+            }
+            self.handlers.insert(method.to_string(), handler);
+            self.meta.insert(method.to_string(), meta);
+            return Ok(());
+        }
+        let segment = segments[0];
+        if segment == ":" {
+            return Err(RouteError::Invalid(format!("{}: parameter segments need a name, like `:id`", pattern)));
+        }
+        if segment.starts_with('*') {
+            if segments.len() > 1 {
+                return Err(RouteError::Invalid(format!("{}: a catch-all must be the last segment", pattern)));
+            }
+            if let Some(wildcard) = &self.wildcard {
+                if wildcard.segment != segment {
+                    return Err(RouteError::Conflict(format!("{}: `{}` conflicts with `{}` registered at the same position", pattern, segment, wildcard.segment)));
+                }
+            }
+            let child = self.wildcard.get_or_insert_with(|| Box::new(RouteNode::new(segment)));
+            return child.insert(&[], method, handler, meta, pattern);
+        }
+        let child = if segment.starts_with(':') {
+            if let Some(param) = &self.param {
+                if param.segment != segment {
+                    return Err(RouteError::Conflict(format!("{}: `{}` conflicts with `{}` registered at the same position", pattern, segment, param.segment)));
+                }
+            }
+            self.param.get_or_insert_with(|| Box::new(RouteNode::new(segment)))
+        } else {
+            let position = self.literals.iter().position(|node| node.segment == segment);
+            match position {
+                Some(position) => &mut self.literals[position],
+                None => {
+                    self.literals.push(RouteNode::new(segment));
+                    self.literals.last_mut().unwrap()
+                },
+            }
+        };
+        return child.insert(&segments[1..], method, handler, meta, pattern);
+    }
+
+    /// Finds the node matching `segments` that has a handler for `method`, collecting any parameters along the way.
+    /// At each depth a literal segment is tried first, then a parameter, then a catch-all.
+    pub fn find(&self, segments: &[&str], method: &str, params: &mut RouteParams, wildcard: &mut Option<String>) -> Option<&RouteNode> {
+        if segments.is_empty() && self.handlers.contains_key(method) {
+            return Some(self);
+        }
+        if segments.is_empty() {
+            return self.find_wildcard(segments, method, params, wildcard);
+        }
+        let segment = segments[0];
+        for literal in &self.literals {
+            if literal.segment != segment {
+                continue;
+            }
+            if let Some(node) = literal.find(&segments[1..], method, params, wildcard) {
+                return Some(node);
+            }
+        }
+        if let Some(param) = &self.param {
+            params.push((param.param_name().unwrap_or("").to_string(), decode_url_part(segment)));
+            if let Some(node) = param.find(&segments[1..], method, params, wildcard) {
+                return Some(node);
+            }
+            params.pop();
+        }
+        return self.find_wildcard(segments, method, params, wildcard);
+    }
+
+    /// Pushes the methods of every node that matches `segments`, whatever the method.
+    pub fn allowed(&self, segments: &[&str], methods: &mut Vec<String>) {
+        if let Some(wildcard) = &self.wildcard {
+            methods.extend(wildcard.handlers.keys().cloned());
+        }
+        if segments.is_empty() {
+            methods.extend(self.handlers.keys().cloned());
+            return;
+        }
+        let segment = segments[0];
+        for literal in &self.literals {
+            if literal.segment == segment {
+                literal.allowed(&segments[1..], methods);
+            }
+        }
+        if let Some(param) = &self.param {
+            param.allowed(&segments[1..], methods);
+        }
+    }
+
+    /// Walks this node and its children, pushing every registered route onto `entries`.
+    pub fn collect(&self, path: &str, entries: &mut Vec<RouteEntry>) {
+        let mut methods: Vec<&String> = self.handlers.keys().collect();
+        methods.sort();
+        for method in methods {
+            let route_path = if path.is_empty() { "/".to_string() } else { path.to_string() };
+            entries.push(RouteEntry {
+                method: method.to_string(),
+                path: route_path,
+                handler: Arc::clone(&self.handlers[method]),
+                meta: self.meta.get(method).cloned().unwrap_or_default(),
+            });
+        }
+        for literal in &self.literals {
+            literal.collect(&format!("{}/{}", path, literal.segment), entries);
+        }
+        if let Some(param) = &self.param {
+            param.collect(&format!("{}/{}", path, param.segment), entries);
+        }
+        if let Some(wildcard) = &self.wildcard {
+            wildcard.collect(&format!("{}/{}", path, wildcard.segment), entries);
+        }
+    }
+
+    /// Matches the remaining `segments`, which may be empty, against this node's catch-all.
+    fn find_wildcard(&self, segments: &[&str], method: &str, params: &mut RouteParams, wildcard: &mut Option<String>) -> Option<&RouteNode> {
+        let node = self.wildcard.as_ref()?;
+        if !node.handlers.contains_key(method) {
+            return None;
+        }
+        let remainder = decode_url_part(&segments.join("/"));
+        let name = node.param_name().unwrap_or("");
+        if !name.is_empty() {
+            params.push((name.to_string(), remainder.clone()));
+        }
+        *wildcard = Some(remainder);
+        return Some(node);
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteTree {
+    pub root: RouteNode,
+}
+
+impl Default for RouteTree {
+    fn default() -> Self {
+        return RouteTree::new();
+    }
+}
+
+impl RouteTree {
+    pub fn new() -> RouteTree {
+        RouteTree {
+            root: RouteNode::new(""),
+        }
+    }
+
+    pub fn insert(&mut self, pattern: &str, handler: Arc<RouteHandler>, meta: RouteMeta) -> Result<(), RouteError> {
+        let (method, path) = split_pattern(pattern);
+        if method.is_empty() || !path.starts_with('/') {
+            return Err(RouteError::Invalid(format!("{}: patterns look like `GET /path`", pattern)));
+        }
+        let segments = path_segments(path);
+        return self.root.insert(&segments, method, handler, meta, pattern);
+    }
+
+    /// Every registered route, literals before parameters before catch-alls.
+    pub fn entries(&self) -> Vec<RouteEntry> {
+        let mut entries: Vec<RouteEntry> = vec![];
+        self.root.collect("", &mut entries);
+        return entries;
+    }
+
+    /// The methods `path` can be requested with, for the `Allow` header.
+    /// Empty when no route matches the path at all. HEAD is implied by GET and OPTIONS is always answered.
+    pub fn allowed(&self, path: &str) -> Vec<String> {
+        let segments = path_segments(path);
+        let mut methods: Vec<String> = vec![];
+        self.root.allowed(&segments, &mut methods);
+        if methods.is_empty() {
+            return methods;
+        }
+        if methods.iter().any(|method| method == "GET") {
+            methods.push("HEAD".to_string());
+        }
+        methods.push("OPTIONS".to_string());
+        methods.sort();
+        methods.dedup();
+        return methods;
+    }
+
+    /// Looks up the handler for `method` and `path`, returning it along with the matched path parameters.
+    pub fn get(&self, method: &str, path: &str) -> Option<RouteMatch> {
+        let segments = path_segments(path);
+        let mut params: RouteParams = vec![];
+        let mut wildcard: Option<String> = None;
+        let node = self.root.find(&segments, method, &mut params, &mut wildcard)?;
+        let handler = node.handlers.get(method)?;
+        return Some(RouteMatch {
+            handler: Arc::clone(handler),
+            params,
+            wildcard,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::handler::Handler;
+    use crate::http::response::Response;
+
+    /// A handler that answers with `body`, so a test can tell which route matched.
+    fn route(body: &'static str) -> Arc<RouteHandler> {
+        let handler = Handler::new(move |request| {
+            Box::pin(async move {
+                return (request, Response::new().body(body));
+            })
+        });
+        return Arc::new((handler, vec![], vec![]));
+    }
+
+    fn tree(patterns: &[&'static str]) -> RouteTree {
+        let mut tree = RouteTree::new();
+        for pattern in patterns {
+            tree.insert(pattern, route(pattern), RouteMeta::default()).unwrap();
+        }
+        return tree;
+    }
+
+    /// The pattern of the route that matched, as recorded in its handler.
+    async fn matched(tree: &RouteTree, method: &str, path: &str) -> Option<(String, RouteParams, Option<String>)> {
+        let route_match = tree.get(method, path)?;
+        let (handler, _, _) = &*route_match.handler;
+        let (_, response) = (handler.func)(crate::http::request::Request::new(&"".to_string())).await;
+        return Some((response.body, route_match.params, route_match.wildcard));
+    }
+
+    #[tokio::test]
+    async fn literal_beats_param() {
+        let tree = tree(&["GET /users/:id", "GET /users/me"]);
+        let (pattern, params, _) = matched(&tree, "GET", "/users/me").await.unwrap();
+        assert_eq!(pattern, "GET /users/me");
+        assert!(params.is_empty());
+        let (pattern, params, _) = matched(&tree, "GET", "/users/42").await.unwrap();
+        assert_eq!(pattern, "GET /users/:id");
+        assert_eq!(params, vec![("id".to_string(), "42".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn backtracks_from_literal_to_param() {
+        let tree = tree(&["GET /users/me/settings", "GET /users/:id/posts"]);
+        let (pattern, params, _) = matched(&tree, "GET", "/users/me/posts").await.unwrap();
+        assert_eq!(pattern, "GET /users/:id/posts");
+        assert_eq!(params, vec![("id".to_string(), "me".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn catch_all_captures_remainder() {
+        let tree = tree(&["GET /static/*path", "GET /static/index.html"]);
+        let (pattern, params, wildcard) = matched(&tree, "GET", "/static/css/site.css").await.unwrap();
+        assert_eq!(pattern, "GET /static/*path");
+        assert_eq!(params, vec![("path".to_string(), "css/site.css".to_string())]);
+        assert_eq!(wildcard, Some("css/site.css".to_string()));
+        let (pattern, _, _) = matched(&tree, "GET", "/static/index.html").await.unwrap();
+        assert_eq!(pattern, "GET /static/index.html");
+        let (_, _, wildcard) = matched(&tree, "GET", "/static").await.unwrap();
+        assert_eq!(wildcard, Some("".to_string()));
+    }
+
+    #[tokio::test]
+    async fn decodes_captured_values() {
+        let tree = tree(&["GET /users/:id", "GET /files/*path"]);
+        let (_, params, _) = matched(&tree, "GET", "/users/a%20b%2Fc").await.unwrap();
+        assert_eq!(params, vec![("id".to_string(), "a b/c".to_string())]);
+        let (_, _, wildcard) = matched(&tree, "GET", "/files/my%20docs/r%C3%A9sum%C3%A9.pdf").await.unwrap();
+        assert_eq!(wildcard, Some("my docs/résumé.pdf".to_string()));
+    }
+
+    #[test]
+    fn decode_keeps_invalid_escapes() {
+        assert_eq!(decode_url_part("100%"), "100%");
+        assert_eq!(decode_url_part("%zz%4"), "%zz%4");
+        assert_eq!(decode_url_part("%+1"), "%+1");
+        assert_eq!(decode_url_part("a%2fb"), "a/b");
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let tree = tree(&["GET /users/:id", "DELETE /users/:id", "POST /users"]);
+        assert_eq!(tree.allowed("/users/42"), vec!["DELETE", "GET", "HEAD", "OPTIONS"]);
+        assert_eq!(tree.allowed("/users"), vec!["OPTIONS", "POST"]);
+        assert!(tree.allowed("/nope").is_empty());
+    }
+
+    #[test]
+    fn method_mismatch_is_not_found() {
+        let tree = tree(&["GET /users/:id"]);
+        assert!(tree.get("POST", "/users/42").is_none());
+    }
+}
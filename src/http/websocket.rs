@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::http::request::{HttpMethod, Request};
+use crate::http::response::{Response, Upgraded};
+
+pub use tokio_tungstenite::tungstenite::Message;
+
+/// An upgraded WebSocket connection. It is a `Stream` of incoming `Message`s and a `Sink` for outgoing ones,
+/// and can be split into the two with `StreamExt::split`.
+pub type WebSocket = WebSocketStream<Upgraded>;
+pub type WebSocketFunc = dyn Fn(Request, WebSocket) -> BoxFuture<'static, ()> + Send + Sync + 'static;
+
+/// The only version of the WebSocket protocol there is, RFC 6455.
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Whether a comma separated header value contains `token`, ignoring case.
+fn has_token(value: &str, token: &str) -> bool {
+    return value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token));
+}
+
+/// Answers a WebSocket handshake. A valid one gets `101 Switching Protocols` with the `Sec-WebSocket-Accept` derived
+/// from the client's key, and once that is written `func` is given the request and the upgraded connection.
+/// A request that isn't a WebSocket handshake at all, or asks for another protocol version, gets 426,
+/// and a malformed handshake gets 400.
+pub fn websocket_response(request: &Request, func: Arc<WebSocketFunc>) -> Response {
+    let upgrade = request.get_header_ignore_case("Upgrade");
+    if !has_token(&upgrade, "websocket") || request.protocol != "HTTP/1.1" {
+        return Response::new()
+            .status(426)
+            .set_header("Upgrade", "websocket")
+            .body("this route only accepts WebSocket connections");
+    }
+    if request.get_header_ignore_case("Sec-WebSocket-Version") != WEBSOCKET_VERSION {
+        return Response::new()
+            .status(426)
+            .set_header("Sec-WebSocket-Version", WEBSOCKET_VERSION)
+            .body("unsupported WebSocket version");
+    }
+    let key = request.get_header_ignore_case("Sec-WebSocket-Key");
+    let connection = request.get_header_ignore_case("Connection");
+    if !matches!(request.method, HttpMethod::GET) || key.is_empty() || !has_token(&connection, "upgrade") {
+        return Response::new()
+            .status(400)
+            .body("malformed WebSocket handshake");
+    }
+    let request = request.clone();
+    return Response::new()
+        .set_header("Upgrade", "websocket")
+        .set_header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()))
+        .upgrade(move |upgraded| async move {
+            let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            func(request, socket).await;
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(head: &str) -> Response {
+        let (request, _) = Request::new_from_bytes(head.as_bytes().to_vec());
+        let func: Arc<WebSocketFunc> = Arc::new(|_request, _socket| Box::pin(async {}));
+        return websocket_response(&request, func);
+    }
+
+    fn header(response: &Response, key: &str) -> Option<String> {
+        return response.headers.get(key).map(|value| value.to_string());
+    }
+
+    #[test]
+    fn accepts_rfc_sample_key() {
+        // the example handshake from RFC 6455 §1.3
+        let response = handshake("GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        assert_eq!(response.status, 101);
+        assert_eq!(header(&response, "Sec-WebSocket-Accept").as_deref(), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(header(&response, "Upgrade").as_deref(), Some("websocket"));
+    }
+
+    #[test]
+    fn rejects_plain_requests_with_426() {
+        let response = handshake("GET /chat HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(response.status, 426);
+        assert_eq!(header(&response, "Upgrade").as_deref(), Some("websocket"));
+        let response = handshake("GET /chat HTTP/1.0\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        assert_eq!(response.status, 426);
+    }
+
+    #[test]
+    fn rejects_other_versions_with_426() {
+        let response = handshake("GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 8\r\n\r\n");
+        assert_eq!(response.status, 426);
+        assert_eq!(header(&response, "Sec-WebSocket-Version").as_deref(), Some("13"));
+    }
+
+    #[test]
+    fn rejects_malformed_handshakes_with_400() {
+        let missing_key = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let wrong_method = "POST /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let no_connection_upgrade = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive\r\nSec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        for head in [missing_key, wrong_method, no_connection_upgrade] {
+            assert_eq!(handshake(head).status, 400, "{}", head);
+        }
+    }
+}
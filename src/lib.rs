@@ -1,7 +1,14 @@
 pub mod http;
 pub mod examples;
 
-pub use http::router::{Router, Route};
+pub use http::router::{Router, Route, RouteInfo, UrlError};
+pub use http::tree::RouteError;
 pub use http::handler::Handler;
 pub use http::response::Response;
+pub use http::websocket::{WebSocket, Message};
+pub use http::sse::Event;
 pub use http::middleware::{Middleware, MiddlewareGroup};
+pub use http::request::HttpContext;
+pub use http::shutdown::shutdown_signal;
+pub use http::server::ServerHandle;
+pub use http::config::{ServerConfig, ConfigError, OverloadPolicy};
@@ -4,6 +4,7 @@
 
 
 use zeke::http::router::{Route, Router};
+use zeke::ServerConfig;
 
 use zeke::examples::{
     handlers::{handle_home},
@@ -22,7 +23,13 @@ async fn main() {
         .group(mw_group_trace().await)
     );
 
-	let err = r.serve(&host).await;
+	let config = ServerConfig::from_env();
+	if config.is_err() {
+		println!("Error: {}", config.unwrap_err());
+		return;
+	}
+
+	let err = r.serve_with(&host, config.unwrap()).await;
 	if err.is_err() {
 		println!("Error: {:?}", err);
 	}
@@ -0,0 +1,64 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use zeke::http::router::{Route, Router};
+use zeke::http::server::ServerHandle;
+use zeke::{Handler, HttpContext, Response};
+
+/// Echoes the request body, and answers requests that can't be read with a 400 naming the reason.
+async fn spawn_server() -> ServerHandle {
+    let mut router = Router::new();
+    router.add(Route::new("POST /", Handler::new(|request| Box::pin(async move {
+        let body = request.body.clone();
+        return (request, Response::new().status(200).body(&body));
+    }))));
+    router.bad_request(Handler::new(|request| Box::pin(async move {
+        let body = format!("bad_request: {}", request.get_context(HttpContext::Error));
+        return (request, Response::new().status(400).body(&body));
+    })));
+    return router.spawn("127.0.0.1:0").await.unwrap();
+}
+
+/// Sends `request` on a fresh connection and reads until the server closes it.
+async fn send(server: &ServerHandle, request: &str) -> String {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).await;
+    return response;
+}
+
+#[tokio::test]
+async fn content_length_must_be_digits() {
+    let server = spawn_server().await;
+    let response = send(&server, "POST / HTTP/1.1\r\nContent-Length: +5\r\nConnection: close\r\n\r\nhello").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert!(response.ends_with("bad_request: malformed request: Content-Length is not a number"), "{}", response);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn conflicting_content_lengths() {
+    let server = spawn_server().await;
+    let response = send(&server, "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 3\r\nConnection: close\r\n\r\nhello").await;
+    assert!(response.ends_with("bad_request: malformed request: Content-Length values disagree"), "{}", response);
+    let response = send(&server, "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("hello"), "{}", response);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn broken_chunks_reach_bad_request() {
+    let server = spawn_server().await;
+    let response = send(&server, "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n3\r\nabcX\r\n").await;
+    assert!(response.ends_with("bad_request: malformed request: chunk data was not followed by CRLF"), "{}", response);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn limits_keep_their_status() {
+    let server = spawn_server().await;
+    let response = send(&server, "POST / HTTP/1.1\r\nContent-Length: 99999999\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use zeke::http::config::ServerConfig;
+use zeke::http::router::{Route, Router};
+use zeke::http::server::ServerHandle;
+use zeke::{Handler, Response};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_DATA: u8 = 0x0;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+/// Big enough that its header block doesn't fit in one 16KB frame.
+const BIG_HEADER: usize = 20_000;
+
+/// Answers with the protocol the request came in on and the length of its `X-Big` header.
+async fn spawn_server() -> ServerHandle {
+    let mut router = Router::new();
+    router.add(Route::new("GET /", Handler::new(|request| Box::pin(async move {
+        let body = format!("{} {}", request.protocol, request.get_header_ignore_case("X-Big").len());
+        return (request, Response::new().status(200).body(&body));
+    }))));
+    router.config = ServerConfig::new().http2(true).max_header_size(64 * 1024);
+    return router.spawn("127.0.0.1:0").await.unwrap();
+}
+
+/// Sends an HTTP/1.1 request asking to upgrade to h2c and reads the response head.
+async fn upgrade(server: &ServerHandle, settings: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\nX-Big: {}\r\n\r\n",
+        settings,
+        "x".repeat(BIG_HEADER),
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head: Vec<u8> = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    return (stream, String::from_utf8_lossy(&head).to_string());
+}
+
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    return frame;
+}
+
+/// Reads one frame as its type, flags, stream id and payload, or `None` if nothing arrives for a while.
+async fn read_frame(stream: &mut TcpStream) -> Option<(u8, u8, u32, Vec<u8>)> {
+    let mut header = [0; 9];
+    timeout(Duration::from_millis(300), stream.read_exact(&mut header)).await.ok()?.ok()?;
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await.ok()?;
+    return Some((header[3], header[4], stream_id, payload));
+}
+
+/// Reads frames until the connection goes quiet, returning the body sent on stream 1 and how many SETTINGS acks came.
+async fn read_stream_one(stream: &mut TcpStream) -> (Vec<u8>, usize) {
+    let mut body: Vec<u8> = vec![];
+    let mut acks = 0;
+    while let Some((kind, flags, stream_id, payload)) = read_frame(stream).await {
+        if kind == FRAME_SETTINGS && flags & FLAG_ACK != 0 {
+            acks += 1;
+        } else if kind == FRAME_SETTINGS {
+            stream.write_all(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[])).await.unwrap();
+        } else if kind == FRAME_DATA && stream_id == 1 {
+            body.extend_from_slice(&payload);
+            if flags & FLAG_END_STREAM != 0 {
+                break;
+            }
+        }
+    }
+    return (body, acks);
+}
+
+#[tokio::test]
+async fn prior_knowledge_with_continuation() {
+    let server = spawn_server().await;
+    let socket = TcpStream::connect(server.local_addr()).await.unwrap();
+    let (mut client, connection) = h2::client::handshake(socket).await.unwrap();
+    tokio::spawn(connection);
+    let request = http::Request::get("http://localhost/")
+        .header("x-big", "x".repeat(BIG_HEADER))
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), 200);
+    let mut body = response.into_body();
+    let mut bytes: Vec<u8> = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk: Bytes = chunk.unwrap();
+        let _ = body.flow_control().release_capacity(chunk.len());
+        bytes.extend_from_slice(&chunk);
+    }
+    assert_eq!(String::from_utf8_lossy(&bytes), format!("HTTP/2.0 {}", BIG_HEADER));
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn upgrade_with_continuation() {
+    let server = spawn_server().await;
+    // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+    let (mut stream, head) = upgrade(&server, "AAMAAABk").await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    stream.write_all(PREFACE).await.unwrap();
+    stream.write_all(&frame(FRAME_SETTINGS, 0, 0, &[])).await.unwrap();
+    let (body, acks) = read_stream_one(&mut stream).await;
+    assert_eq!(String::from_utf8_lossy(&body), format!("HTTP/2.0 {}", BIG_HEADER));
+    assert_eq!(acks, 1);
+    // this client doesn't answer the PING a graceful shutdown sends, so close it rather than wait out the timeout
+    drop(stream);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn upgrade_applies_http2_settings() {
+    let server = spawn_server().await;
+    // SETTINGS_INITIAL_WINDOW_SIZE = 4, so only 4 bytes of the response may be sent before a WINDOW_UPDATE
+    let (mut stream, head) = upgrade(&server, "AAQAAAAE").await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    stream.write_all(PREFACE).await.unwrap();
+    stream.write_all(&frame(FRAME_SETTINGS, 0, 0, &[])).await.unwrap();
+    let (body, acks) = read_stream_one(&mut stream).await;
+    assert_eq!(String::from_utf8_lossy(&body), "HTTP");
+    assert_eq!(acks, 1);
+    stream.write_all(&frame(FRAME_WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes())).await.unwrap();
+    let (body, _) = read_stream_one(&mut stream).await;
+    assert_eq!(String::from_utf8_lossy(&body), format!("/2.0 {}", BIG_HEADER));
+    drop(stream);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn invalid_http2_settings_stay_on_http1() {
+    let server = spawn_server().await;
+    // 4 bytes is not a whole number of settings
+    let (_stream, head) = upgrade(&server, "AAMAAA").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Instant};
+use zeke::http::router::{Route, Router};
+use zeke::http::server::ServerHandle;
+use zeke::{Event, Handler, Response};
+
+/// Counts how many event streams have been dropped.
+struct DropGuard(Arc<AtomicUsize>);
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves an event stream that sends one event and then goes quiet for good, without keep-alive comments.
+async fn spawn_server(dropped: Arc<AtomicUsize>) -> ServerHandle {
+    let mut router = Router::new();
+    router.add(Route::new("GET /events", Handler::new(move |request| {
+        let guard = DropGuard(Arc::clone(&dropped));
+        Box::pin(async move {
+            let events = stream::unfold((guard, false), |(guard, sent)| async move {
+                if sent {
+                    std::future::pending::<()>().await;
+                }
+                return Some((Event::new("first"), (guard, true)));
+            });
+            return (request, Response::sse(events));
+        })
+    })));
+    return router.spawn("127.0.0.1:0").await.unwrap();
+}
+
+/// Opens the event stream and reads until the first event has arrived.
+async fn subscribe(server: &ServerHandle) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut received: Vec<u8> = vec![];
+    while !String::from_utf8_lossy(&received).contains("data: first") {
+        let mut chunk = [0; 1024];
+        let bytes_read = stream.read(&mut chunk).await.unwrap();
+        assert!(bytes_read > 0, "connection closed early");
+        received.extend_from_slice(&chunk[..bytes_read]);
+    }
+    return (stream, String::from_utf8_lossy(&received).to_string());
+}
+
+#[tokio::test]
+async fn idle_stream_is_dropped_when_the_client_leaves() {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let server = spawn_server(Arc::clone(&dropped)).await;
+    let (stream, _) = subscribe(&server).await;
+    drop(stream);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while dropped.load(Ordering::SeqCst) == 0 {
+        assert!(Instant::now() < deadline, "the event stream outlived its client");
+        sleep(Duration::from_millis(10)).await;
+    }
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_ends_idle_stream() {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let server = spawn_server(Arc::clone(&dropped)).await;
+    let (mut stream, _) = subscribe(&server).await;
+    // well under the 30 second shutdown_timeout an open stream would otherwise hold the server for
+    timeout(Duration::from_secs(2), server.shutdown()).await.unwrap().unwrap();
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    let mut rest = String::new();
+    let _ = stream.read_to_string(&mut rest).await;
+    assert!(rest.ends_with("0\r\n\r\n"), "{:?}", rest);
+}
@@ -0,0 +1,106 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use zeke::http::router::{Route, Router};
+use zeke::http::tls::CertReloader;
+use zeke::{Handler, Response};
+
+/// A fresh directory under the system temp directory, so parallel tests don't share files.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zeke-tls-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+/// Writes a self-signed certificate for `localhost` and its key, returning the certificate's DER bytes.
+fn write_cert(dir: &Path) -> Vec<u8> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+    return certified.cert.der().to_vec();
+}
+
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    return listener.local_addr().unwrap().to_string();
+}
+
+#[tokio::test]
+async fn serves_https() {
+    let dir = temp_dir("serve");
+    let der = write_cert(&dir);
+    let mut router = Router::new();
+    router.add(Route::new("GET /", Handler::new(|request| Box::pin(async move {
+        return (request, Response::new().status(200).body("secure"));
+    }))));
+    let addr = free_addr();
+    let (shutdown, signal) = oneshot::channel::<()>();
+    let cert_path = dir.join("cert.pem").display().to_string();
+    let key_path = dir.join("key.pem").display().to_string();
+    let server_addr = addr.clone();
+    let server = tokio::spawn(async move {
+        return router.serve_tls_with_shutdown(&server_addr, &cert_path, &key_path, async move {
+            let _ = signal.await;
+        }).await;
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots.add(der.into()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(tcp) = TcpStream::connect(&addr).await {
+            stream = Some(tcp);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let domain = ServerName::try_from("localhost").unwrap();
+    let mut tls = connector.connect(domain, stream.expect("server never started listening")).await.unwrap();
+    tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = vec![];
+    // the server may close without a close_notify, which rustls reports as an error after the data
+    let _ = tls.read_to_end(&mut response).await;
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("secure"), "{}", response);
+
+    let _ = shutdown.send(());
+    server.await.unwrap().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reloads_changed_certificate() {
+    let dir = temp_dir("reload");
+    let first = write_cert(&dir);
+    let reloader = CertReloader::new(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+    assert_eq!(reloader.certified_key().unwrap().cert[0].as_ref(), first.as_slice());
+
+    let second = write_cert(&dir);
+    // file times can be too coarse to tell two quick writes apart, so move them forward explicitly
+    let later = SystemTime::now() + Duration::from_secs(10);
+    File::options().write(true).open(dir.join("cert.pem")).unwrap().set_modified(later).unwrap();
+    File::options().write(true).open(dir.join("key.pem")).unwrap().set_modified(later).unwrap();
+    assert_eq!(reloader.certified_key().unwrap().cert[0].as_ref(), second.as_slice());
+
+    // a half-written certificate keeps the previous one in use
+    fs::write(dir.join("cert.pem"), "not a certificate").unwrap();
+    File::options().write(true).open(dir.join("cert.pem")).unwrap().set_modified(later + Duration::from_secs(10)).unwrap();
+    assert_eq!(reloader.certified_key().unwrap().cert[0].as_ref(), second.as_slice());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,66 @@
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use zeke::http::router::{Route, Router};
+use zeke::http::server::ServerHandle;
+use zeke::{Handler, Message, Middleware, Response};
+
+/// Echoes text messages back prefixed with the `session` cookie, behind middleware that wants the cookie set.
+async fn spawn_server() -> ServerHandle {
+    let mut router = Router::new();
+    router.middleware(Middleware::new(|request| {
+        if request.get_cookie("session").is_empty() {
+            return Some(Response::new().status(401).body("no session"));
+        }
+        return None;
+    }));
+    router.add(Route::new("GET /chat", Handler::websocket(|request, mut socket| {
+        Box::pin(async move {
+            let user = request.get_cookie("session");
+            while let Some(Ok(message)) = socket.next().await {
+                if let Message::Text(text) = message {
+                    if socket.send(Message::Text(format!("{}: {}", user, text))).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        })
+    })));
+    return router.spawn("127.0.0.1:0").await.unwrap();
+}
+
+#[tokio::test]
+async fn round_trip() {
+    let server = spawn_server().await;
+    let tcp = TcpStream::connect(server.local_addr()).await.unwrap();
+    let mut request = "ws://localhost/chat".into_client_request().unwrap();
+    request.headers_mut().insert("Cookie", "session=alice".parse().unwrap());
+    let (mut socket, response) = client_async(request, tcp).await.unwrap();
+    assert_eq!(response.status(), 101);
+    socket.send(Message::Text("hi".to_string())).await.unwrap();
+    let reply = socket.next().await.unwrap().unwrap();
+    assert_eq!(reply, Message::Text("alice: hi".to_string()));
+    socket.close(None).await.unwrap();
+    // the server answers the close, then the stream ends
+    while let Some(message) = socket.next().await {
+        assert!(matches!(message, Ok(Message::Close(_))), "{:?}", message);
+    }
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn middleware_rejects_before_upgrade() {
+    let server = spawn_server().await;
+    let tcp = TcpStream::connect(server.local_addr()).await.unwrap();
+    match client_async("ws://localhost/chat", tcp).await {
+        Err(WsError::Http(response)) => {
+            assert_eq!(response.status(), 401);
+        },
+        other => {
+            panic!("expected a 401, got {:?}", other.map(|(_, response)| response));
+        },
+    }
+    server.shutdown().await.unwrap();
+}